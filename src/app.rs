@@ -1,40 +1,94 @@
 use std::{
-    collections::HashMap,
     fs::{self, File},
     io::Write,
 };
 
-use crate::{
-    automaton::Automaton,
-    cellang,
-    graph::{Graph, Node},
+use cellgraph::{
+    automaton::{Automaton, RunOutcome, Snapshot, UpdateMode},
+    cellang::{self, Diagnostic},
+    clock::{Clock, ClockMessage, MidiClock, SyncMode, Tempo, BPM_RANGE, MIDI_CLOCKS_PER_BEAT},
+    graph::{Edge, Graph, Node, NodeId, State},
+    history::{Edit, History},
+    midi::{MidiSink, MidiSource, NoteInput, Player},
+    osc::{OscSender, OscSettings},
     saved_state::SavedState,
+    smf,
+    synth::{SynthSettings, Waveform},
+    tuning::{Scale, Tuning},
     vec2::Vector2,
+    wav,
 };
-use macroquad::prelude::*;
 
 use egui_macroquad::egui::{
-    self, Color32, ComboBox, FontDefinitions, FontFamily, FontSelection, Grid, Id, Pos2, Response,
-    Sense, Separator, Stroke, Ui, Vec2, Widget, WidgetWithState,
+    self,
+    text::{LayoutJob, TextFormat},
+    Color32, Grid, Id, Separator, TextEdit, TextStyle,
 };
 use macroquad::prelude::*;
-use serde::{Serialize, Serializer};
+
+use crate::inspector::Inspector;
+
+/// The colour of each cell state, repeating for states past the end.
+const STATE_COLORS: [Color; 8] = [
+    Color::new(0.2, 0.7, 0.9, 1.0),
+    Color::new(0.71, 0.643, 0.451, 1.0),
+    Color::new(0.85, 0.35, 0.3, 1.0),
+    Color::new(0.45, 0.75, 0.4, 1.0),
+    Color::new(0.6, 0.45, 0.8, 1.0),
+    Color::new(0.95, 0.75, 0.3, 1.0),
+    Color::new(0.9, 0.5, 0.7, 1.0),
+    Color::new(0.5, 0.5, 0.5, 1.0),
+];
 
 pub struct App {
     automaton: Automaton,
     offset: Vector2,
     zoom: f32,
-    pub selected: Vec<usize>,
+    pub selected: Vec<NodeId>,
     prev_mouse_position: Vector2,
-    dragging_connection: Option<usize>,
+    dragging_connection: Option<NodeId>,
     playing: bool,
-    adding_state: bool,
+    /// The states from before playback started, to reset to.
+    snapshot: Option<Snapshot>,
+    run_steps: usize,
+    run_outcome: Option<RunOutcome>,
+    tempo: Tempo,
+    tuning: Tuning,
+    synth: SynthSettings,
+    /// The text of the reference note field, which may not be a note yet.
+    reference_note: String,
+    clock: Clock,
+    sync: SyncMode,
+    /// Counts the MIDI clocks received or sent.
+    midi_clock: MidiClock,
+    adding_state: State,
     adding_type: String,
     ui_hovering: bool,
-    showing_code: bool,
     code: String,
+    diagnostics: Vec<Diagnostic>,
     clipboard: Option<Graph>,
     box_select: Option<Vector2>,
+    history: History,
+    inspector: Inspector,
+    /// How far the selection has been dragged since the mouse was pressed.
+    dragged: Vector2,
+    midi_out: Option<Box<dyn MidiSink>>,
+    player: Player,
+    /// The built-in synth playing live, with its own player since it may
+    /// sound different notes than the MIDI output.
+    #[cfg(all(feature = "audio-out", target_os = "linux"))]
+    audio_out: Option<cellgraph::synth::AudioOutput>,
+    #[cfg(all(feature = "audio-out", target_os = "linux"))]
+    audio_player: Player,
+    midi_ports: Vec<String>,
+    midi_port: String,
+    midi_in: Option<Box<dyn MidiSource>>,
+    note_input: NoteInput,
+    midi_input_ports: Vec<String>,
+    midi_input_port: String,
+    osc_settings: OscSettings,
+    osc: Option<OscSender>,
+    export_steps: usize,
 }
 
 impl App {
@@ -47,13 +101,55 @@ impl App {
             prev_mouse_position: Vector2::zero(),
             dragging_connection: None,
             playing: false,
-            adding_state: false,
+            snapshot: None,
+            run_steps: 10,
+            run_outcome: None,
+            tempo: Tempo::default(),
+            tuning: Tuning::default(),
+            synth: SynthSettings::default(),
+            reference_note: Tuning::default().reference.to_string(),
+            clock: Clock::new(),
+            sync: SyncMode::Internal,
+            midi_clock: MidiClock::new(),
+            adding_state: 0,
             adding_type: String::new(),
             ui_hovering: false,
-            showing_code: true,
             code: String::new(),
+            diagnostics: vec![],
             clipboard: None,
             box_select: None,
+            history: History::new(),
+            inspector: Inspector::new(),
+            dragged: Vector2::zero(),
+            midi_out: None,
+            player: Player::new(),
+            #[cfg(all(feature = "audio-out", target_os = "linux"))]
+            audio_out: None,
+            #[cfg(all(feature = "audio-out", target_os = "linux"))]
+            audio_player: Player::new(),
+            #[cfg(feature = "midi-port")]
+            midi_ports: cellgraph::midi::MidiPort::port_names().unwrap_or_else(|error| {
+                println!("unable to list midi ports: {error}");
+                vec![]
+            }),
+            #[cfg(not(feature = "midi-port"))]
+            midi_ports: vec![],
+            midi_port: String::new(),
+            midi_in: None,
+            note_input: NoteInput::new(),
+            #[cfg(feature = "midi-port")]
+            midi_input_ports: cellgraph::midi::MidiInputPort::port_names().unwrap_or_else(
+                |error| {
+                    println!("unable to list midi ports: {error}");
+                    vec![]
+                },
+            ),
+            #[cfg(not(feature = "midi-port"))]
+            midi_input_ports: vec![],
+            midi_input_port: String::new(),
+            osc_settings: OscSettings::default(),
+            osc: None,
+            export_steps: 64,
         }
     }
 
@@ -62,13 +158,40 @@ impl App {
     }
 
     fn screen_to_world_coord(&self, vec: Vector2) -> Vector2 {
-        (self.offset * self.zoom - vec) / (-1.0 * self.zoom)
+        (self.offset * self.zoom - vec) / -self.zoom
     }
 
     pub async fn mainloop(&mut self) {
+        if let Some(midi_in) = &mut self.midi_in {
+            let messages = midi_in.receive();
+            self.note_input
+                .handle_messages(&messages, &mut self.automaton.graph);
+            if self.sync == SyncMode::Follow {
+                for message in messages.iter().filter_map(|m| ClockMessage::decode(m)) {
+                    self.follow_clock(message);
+                }
+            }
+        }
         if self.playing {
-            if get_time() % 0.5 < get_frame_time() as f64 {
-                self.automaton.step()
+            match self.sync {
+                SyncMode::Internal => {
+                    for _ in 0..self.clock.advance(&self.tempo, get_frame_time() as f64) {
+                        self.step();
+                    }
+                }
+                SyncMode::Lead => {
+                    let clock_tempo = Tempo {
+                        subdivision: MIDI_CLOCKS_PER_BEAT,
+                        ..self.tempo
+                    };
+                    for _ in 0..self.clock.advance(&clock_tempo, get_frame_time() as f64) {
+                        self.send_clock(ClockMessage::Clock);
+                        for _ in 0..self.midi_clock.tick(&self.tempo) {
+                            self.step();
+                        }
+                    }
+                }
+                SyncMode::Follow => (),
             }
         }
         clear_background(Color::new(0.1, 0.1, 0.1, 1.0));
@@ -80,11 +203,11 @@ impl App {
 
             let (m_x, m_y) = mouse_position();
 
-            for (i, node) in self.automaton.graph.nodes.iter().enumerate() {
+            for (id, node) in &self.automaton.graph.nodes {
                 if (self.world_to_screen_coord(node.position) - Vector2::new(m_x, m_y)).length()
                     < 30.0 * self.zoom
                 {
-                    hovering = Some(i);
+                    hovering = Some(*id);
                 }
             }
 
@@ -103,10 +226,11 @@ impl App {
             }
 
             if is_mouse_button_down(MouseButton::Left) {
+                let offset = Vector2::from(mouse_position()) - self.prev_mouse_position;
                 for selected in &self.selected {
-                    self.automaton.graph[*selected].position -=
-                        self.prev_mouse_position - Vector2::from(mouse_position());
+                    self.automaton.graph[*selected].position += offset;
                 }
+                self.dragged += offset;
             }
 
             if is_mouse_button_pressed(MouseButton::Right) {
@@ -118,16 +242,11 @@ impl App {
             if is_mouse_button_released(MouseButton::Right) {
                 if let Some(dragging_connection) = self.dragging_connection {
                     if let Some(hovering) = hovering {
-                        if !self.automaton.graph[hovering]
-                            .edges
-                            .contains(&dragging_connection)
-                        {
-                            self.automaton.graph.add_edge(hovering, dragging_connection);
-                        } else {
-                            self.automaton
-                                .graph
-                                .remove_edge(hovering, dragging_connection);
-                        }
+                        let edit = match self.automaton.graph.edge(hovering, dragging_connection) {
+                            Some(edge) => Edit::RemoveEdge(hovering, edge.clone()),
+                            None => Edit::AddEdge(hovering, Edge::new(dragging_connection)),
+                        };
+                        self.history.apply(edit, &mut self.automaton.graph);
                     };
                 };
                 self.dragging_connection = None
@@ -142,26 +261,21 @@ impl App {
             }
 
             if is_key_pressed(KeyCode::A) {
-                self.automaton.graph.add_node(Node::new(
+                let node = Node::new(
                     self.adding_state,
                     self.adding_state,
                     vec![],
                     self.screen_to_world_coord(Vector2::from(mouse_position())),
                     self.adding_type.clone(),
-                ));
+                );
+                let id = self.automaton.graph.add_node(node.clone());
+                self.history.push(Edit::AddNodes(vec![(id, node)]));
             }
 
-            if is_key_pressed(KeyCode::Delete) {
-                while let Some(selected) = self.selected.pop() {
-                    self.automaton.graph.remove_node(selected);
-
-                    let len = self.automaton.graph.nodes.len();
-                    self.selected = self
-                        .selected
-                        .iter()
-                        .map(|a| if *a == len { selected } else { *a })
-                        .collect();
-                }
+            if is_key_pressed(KeyCode::Delete) && !self.selected.is_empty() {
+                let edit = Edit::remove_nodes(&self.automaton.graph, &self.selected);
+                self.history.apply(edit, &mut self.automaton.graph);
+                self.selected = vec![];
             }
 
             if is_key_down(KeyCode::LeftControl) {
@@ -172,35 +286,48 @@ impl App {
                 if is_key_pressed(KeyCode::V) {
                     // // paste
                     self.selected = vec![];
-                    let len = self.automaton.graph.nodes.len();
-                    if let Some(clipboard) = self.clipboard.clone() {
-                        for node in clipboard.nodes {
-                            let new_node = Node::new(
-                                node.read,
-                                node.write,
-                                node.edges.iter().map(|a| a + len).collect(),
-                                node.position + Vector2::new(50.0, 50.0),
-                                node.ruleset.clone(),
-                            );
+                    if let Some(clipboard) = &self.clipboard {
+                        self.selected = self
+                            .automaton
+                            .graph
+                            .paste(clipboard, Vector2::new(50.0, 50.0));
+                        self.history.push(Edit::AddNodes(
+                            self.selected
+                                .iter()
+                                .map(|id| (*id, self.automaton.graph[*id].clone()))
+                                .collect(),
+                        ));
+                    }
+                }
 
-                            self.automaton.graph.add_node(new_node);
-                            self.selected.push(self.automaton.graph.nodes.len() - 1)
-                        }
+                if is_key_pressed(KeyCode::Z) {
+                    if is_key_down(KeyCode::LeftShift) {
+                        self.history.redo(&mut self.automaton.graph);
+                    } else {
+                        self.history.undo(&mut self.automaton.graph);
                     }
+                    let graph = &self.automaton.graph;
+                    self.selected.retain(|id| graph.nodes.contains_key(id));
                 }
             }
 
-            if let None = hovering {
-                if is_mouse_button_pressed(MouseButton::Left) {
-                    self.box_select = Some(Vector2::from(mouse_position()));
-                }
+            if hovering.is_none() && is_mouse_button_pressed(MouseButton::Left) {
+                self.box_select = Some(Vector2::from(mouse_position()));
             }
         }
 
         if is_mouse_button_released(MouseButton::Left) {
+            if !self.selected.is_empty() && self.dragged.length() > 0.0 {
+                self.history.push(Edit::MoveNodes {
+                    nodes: self.selected.clone(),
+                    offset: self.dragged,
+                });
+            }
+            self.dragged = Vector2::zero();
+
             if let Some(box_drag_start) = self.box_select {
                 let rect = find_rect(
-                    self.screen_to_world_coord(box_drag_start.into()),
+                    self.screen_to_world_coord(box_drag_start),
                     self.screen_to_world_coord(Vector2::from(mouse_position())),
                 );
 
@@ -214,20 +341,19 @@ impl App {
                 }
                 self.box_select = None;
 
-                for (i, position) in self
+                for (id, position) in self
                     .automaton
                     .graph
                     .nodes
                     .iter()
-                    .map(|a| a.position)
-                    .enumerate()
+                    .map(|(id, node)| (*id, node.position))
                 {
                     if position.x >= x_1
                         && position.x <= x_2
                         && position.y >= y_1
                         && position.y <= y_2
                     {
-                        self.selected.push(i)
+                        self.selected.push(id)
                     }
                 }
 
@@ -243,16 +369,88 @@ impl App {
                 egui_ctx,
                 |ui| {
                     Grid::new("top panel gird").show(ui, |ui| {
-                        if ui.checkbox(&mut self.playing, "playing").clicked() {
-                            self.automaton.rules = HashMap::new();
-
-                            for line in self.code.split("\n") {
-                                if let Ok(parsed) = cellang::expr_parser::ruleset(&line) {
-                                    self.automaton.rules.insert(parsed.name.clone(), parsed);
-                                } else {
-                                    println!("unable to parse {:?}", line);
-                                }
+                        let mut playing = self.playing;
+                        if ui.checkbox(&mut playing, "playing").clicked() {
+                            self.set_playing(playing);
+                        }
+                        if ui.button("step").clicked() {
+                            self.start_stepping();
+                            self.step();
+                        }
+                        ui.add(egui::DragValue::new(&mut self.run_steps).clamp_range(1..=10000));
+                        if ui.button("step n").clicked() {
+                            self.start_stepping();
+                            for _ in 0..self.run_steps {
+                                self.step();
+                            }
+                        }
+                        if ui.button("run until stable").clicked() {
+                            self.start_stepping();
+                            self.run_outcome = Some(self.automaton.run_until_cycle(10000));
+                        }
+                        match self.run_outcome {
+                            Some(RunOutcome::Stable { steps }) => {
+                                ui.label(format!("stable after {steps} steps"));
+                            }
+                            Some(RunOutcome::Cycle { steps, period }) => {
+                                ui.label(format!("cycle of {period} after {steps} steps"));
+                            }
+                            Some(RunOutcome::Limit) => {
+                                ui.label("no cycle within 10000 steps");
                             }
+                            None => (),
+                        }
+                        if ui
+                            .add_enabled(self.snapshot.is_some(), egui::Button::new("reset"))
+                            .clicked()
+                        {
+                            if let Some(snapshot) = self.snapshot.take() {
+                                self.automaton.restore(&snapshot);
+                            }
+                            self.stop_notes();
+                            if let Some(osc) = &mut self.osc {
+                                osc.reset();
+                            }
+                            self.run_outcome = None;
+                        }
+                        ui.add(
+                            egui::DragValue::new(&mut self.tempo.bpm)
+                                .clamp_range(BPM_RANGE)
+                                .suffix(" bpm"),
+                        );
+                        ui.add(
+                            egui::DragValue::new(&mut self.tempo.subdivision)
+                                .clamp_range(1..=64)
+                                .suffix(" steps/beat"),
+                        );
+                        egui::ComboBox::from_id_source("sync")
+                            .selected_text(sync_name(self.sync))
+                            .show_ui(ui, |ui| {
+                                for sync in [SyncMode::Internal, SyncMode::Follow, SyncMode::Lead] {
+                                    ui.selectable_value(&mut self.sync, sync, sync_name(sync));
+                                }
+                            });
+                        ui.add(Separator::default().vertical());
+                        egui::ComboBox::from_id_source("update mode")
+                            .selected_text(update_mode_name(self.automaton.update))
+                            .show_ui(ui, |ui| {
+                                for mode in UpdateMode::ALL {
+                                    ui.selectable_value(
+                                        &mut self.automaton.update,
+                                        mode,
+                                        update_mode_name(mode),
+                                    );
+                                }
+                            });
+                        let mut seed = self.automaton.seed;
+                        if ui
+                            .add(egui::DragValue::new(&mut seed).prefix("seed "))
+                            .changed()
+                        {
+                            self.automaton.reseed(seed);
+                        }
+                        if ui.button("reseed").clicked() {
+                            self.automaton.reseed(self.automaton.seed);
                         }
                         ui.add(Separator::default().vertical());
                         if ui.button("save").clicked() {
@@ -261,6 +459,47 @@ impl App {
                         if ui.button("load").clicked() {
                             self.load_graph();
                         }
+                        ui.add(Separator::default().vertical());
+                        ui.add(egui::DragValue::new(&mut self.export_steps).suffix(" steps"));
+                        if ui.button("export midi").clicked() {
+                            self.export_midi();
+                        }
+                        if ui.button("export wav").clicked() {
+                            self.export_wav();
+                        }
+                        ui.add(Separator::default().vertical());
+                        let previous_port = self.midi_port.clone();
+                        egui::ComboBox::from_label("midi out")
+                            .selected_text(&self.midi_port)
+                            .show_ui(ui, |ui| {
+                                ui.selectable_value(&mut self.midi_port, String::new(), "none");
+                                for port in &self.midi_ports {
+                                    ui.selectable_value(&mut self.midi_port, port.clone(), port);
+                                }
+                            });
+                        if self.midi_port != previous_port {
+                            self.connect_midi();
+                        }
+                        let previous_port = self.midi_input_port.clone();
+                        egui::ComboBox::from_label("midi in")
+                            .selected_text(&self.midi_input_port)
+                            .show_ui(ui, |ui| {
+                                ui.selectable_value(
+                                    &mut self.midi_input_port,
+                                    String::new(),
+                                    "none",
+                                );
+                                for port in &self.midi_input_ports {
+                                    ui.selectable_value(
+                                        &mut self.midi_input_port,
+                                        port.clone(),
+                                        port,
+                                    );
+                                }
+                            });
+                        if self.midi_input_port != previous_port {
+                            self.connect_midi_input();
+                        }
                     })
                 },
             );
@@ -269,16 +508,24 @@ impl App {
                     .resizable(true)
                     .show(egui_ctx, |ui| {
                         ui.label("state");
-                        ui.radio_value(&mut self.adding_state, true, "on");
-                        ui.radio_value(&mut self.adding_state, false, "off");
+                        ui.add(egui::DragValue::new(&mut self.adding_state));
                         if ui.button("apply state to selected").clicked() {
-                            for selected in &self.selected {
-                                self.automaton.graph[*selected].write = self.adding_state;
-                            }
+                            let edit = Edit::set_state(
+                                &self.automaton.graph,
+                                &self.selected,
+                                self.adding_state,
+                            );
+                            self.history.apply(edit, &mut self.automaton.graph);
                         }
                         ui.separator();
+                        self.tuning_ui(ui);
+                        ui.separator();
+                        self.synth_ui(ui);
+                        ui.separator();
+                        self.osc_ui(ui);
+                        ui.separator();
                         egui::ComboBox::from_label("adding type")
-                            .selected_text(format!("{}", self.adding_type))
+                            .selected_text(&self.adding_type)
                             .show_ui(ui, |ui| {
                                 for rule in self.automaton.rules.keys() {
                                     ui.selectable_value(&mut self.adding_type, rule.clone(), rule);
@@ -288,19 +535,41 @@ impl App {
                         ui.set_width(100.0);
                         ui.separator();
 
-                        self.ui_hovering |= ui.text_edit_multiline(&mut self.code).has_focus();
+                        let error_lines: Vec<usize> = self
+                            .diagnostics
+                            .iter()
+                            .filter_map(Diagnostic::line)
+                            .collect();
+                        let mut layouter = |ui: &egui::Ui, code: &str, wrap_width: f32| {
+                            let font_id = TextStyle::Monospace.resolve(ui.style());
+                            let mut job = LayoutJob::default();
+                            for (i, line) in code.split_inclusive('\n').enumerate() {
+                                let background = if error_lines.contains(&(i + 1)) {
+                                    Color32::from_rgb(110, 30, 30)
+                                } else {
+                                    Color32::TRANSPARENT
+                                };
+                                job.append(
+                                    line,
+                                    0.0,
+                                    TextFormat {
+                                        font_id: font_id.clone(),
+                                        color: ui.visuals().text_color(),
+                                        background,
+                                        ..Default::default()
+                                    },
+                                );
+                            }
+                            job.wrap.max_width = wrap_width;
+                            ui.fonts(|fonts| fonts.layout_job(job))
+                        };
+                        self.ui_hovering |= ui
+                            .add(TextEdit::multiline(&mut self.code).layouter(&mut layouter))
+                            .has_focus();
 
                         Grid::new("code_grid").show(ui, |ui| {
                             if ui.button("compile code").clicked() {
-                                self.automaton.rules = HashMap::new();
-
-                                for line in self.code.split("\n") {
-                                    if let Ok(parsed) = cellang::expr_parser::ruleset(&line) {
-                                        self.automaton.rules.insert(parsed.name.clone(), parsed);
-                                    } else {
-                                        println!("unable to parse {:?}", line);
-                                    }
-                                }
+                                self.compile_code();
                             }
                             if ui.button("save code").clicked() {
                                 self.save_code();
@@ -309,10 +578,31 @@ impl App {
                                 self.load_code();
                             }
                             ui.end_row();
-                        })
+                        });
+
+                        for diagnostic in self.diagnostics.iter().chain(&cellang::check_references(
+                            &self.automaton.rules,
+                            &self.automaton.graph,
+                        )) {
+                            ui.colored_label(Color32::LIGHT_RED, diagnostic.to_string());
+                        }
                     })
                     .response
                     .hovered();
+            self.ui_hovering |=
+                egui::SidePanel::new(egui::panel::Side::Right, Id::new("inspector"))
+                    .resizable(true)
+                    .show(egui_ctx, |ui| {
+                        self.inspector.show(
+                            ui,
+                            &mut self.automaton,
+                            &self.selected,
+                            &mut self.history,
+                        );
+                    })
+                    .response
+                    .hovered();
+            self.ui_hovering |= egui_ctx.wants_keyboard_input();
         });
 
         // Draw things before egui
@@ -338,16 +628,12 @@ impl App {
                 Color::new(0.8, 0.8, 0.5, 1.0),
             )
         }
-        for node in &self.automaton.graph.nodes {
+        for node in self.automaton.graph.nodes.values() {
             draw_circle(
                 self.world_to_screen_coord(node.position).x,
                 self.world_to_screen_coord(node.position).y,
                 30.0 * self.zoom,
-                if node.write {
-                    Color::new(0.71, 0.643, 0.451, 1.0)
-                } else {
-                    Color::new(0.2, 0.7, 0.9, 1.0)
-                },
+                STATE_COLORS[node.write as usize % STATE_COLORS.len()],
             );
             draw_text(
                 &node.ruleset,
@@ -356,15 +642,47 @@ impl App {
                 18.0 * self.zoom,
                 WHITE,
             );
+            if let Some(note) = &node.note {
+                draw_text(
+                    &note.to_string(),
+                    self.world_to_screen_coord(node.position).x - 12.0 * self.zoom,
+                    self.world_to_screen_coord(node.position).y + 6.0 * self.zoom,
+                    18.0 * self.zoom,
+                    BLACK,
+                );
+            }
         }
 
-        for node in &self.automaton.graph.nodes {
-            for connection in &node.edges {
+        for node in self.automaton.graph.nodes.values() {
+            for edge in &node.edges {
+                // Heavier edges are thicker; inhibiting ones are red.
+                let color = match edge.weight.signum() {
+                    1 => WHITE,
+                    -1 => RED,
+                    _ => GRAY,
+                };
+                let source = self.automaton.graph[edge.source].position;
                 self.draw_arrow_world(
-                    self.automaton.graph[*connection].position,
+                    source,
                     node.position,
                     30.0,
-                )
+                    1.0 + edge.weight.unsigned_abs().min(8) as f32,
+                    color,
+                );
+                let mut label = edge.class.clone();
+                if edge.delay > 0 {
+                    label += &format!(" +{}", edge.delay);
+                }
+                if !label.is_empty() {
+                    let middle = self.world_to_screen_coord((source + node.position) * 0.5);
+                    draw_text(
+                        label.trim_start(),
+                        middle.x + 4.0,
+                        middle.y - 4.0,
+                        16.0,
+                        color,
+                    );
+                }
             }
         }
 
@@ -374,7 +692,14 @@ impl App {
 
         next_frame().await;
     }
-    fn draw_arrow_world(&self, pos1: Vector2, pos2: Vector2, radius: f32) {
+    fn draw_arrow_world(
+        &self,
+        pos1: Vector2,
+        pos2: Vector2,
+        radius: f32,
+        thickness: f32,
+        color: Color,
+    ) {
         let screen_pos2 = self.world_to_screen_coord(pos2);
 
         // Calculate the direction from pos1 to pos2
@@ -393,8 +718,8 @@ impl App {
             screen_circle_start.y,
             screen_circle_end.x,
             screen_circle_end.y,
-            2.0,
-            WHITE,
+            thickness,
+            color,
         );
 
         // Calculate the angle of the arrow
@@ -418,10 +743,278 @@ impl App {
             (screen_pos2 - direction * radius * self.zoom).into(),
             arrow_point1.into(),
             arrow_point2.into(),
-            WHITE,
+            color,
         );
     }
 
+    fn tuning_ui(&mut self, ui: &mut egui::Ui) {
+        ui.label("tuning");
+        ui.horizontal(|ui| {
+            let response =
+                ui.add(TextEdit::singleline(&mut self.reference_note).desired_width(40.0));
+            if response.changed() {
+                if let Ok(note) = self.reference_note.parse() {
+                    self.tuning.reference = note;
+                }
+            }
+            if response.lost_focus() {
+                self.reference_note = self.tuning.reference.to_string();
+            }
+            ui.label("=");
+            ui.add(
+                egui::DragValue::new(&mut self.tuning.frequency)
+                    .clamp_range(1.0..=20000.0)
+                    .suffix(" Hz"),
+            );
+        });
+        egui::ComboBox::from_id_source("scale")
+            .selected_text(self.tuning.scale.name())
+            .show_ui(ui, |ui| {
+                ui.selectable_value(
+                    &mut self.tuning.scale,
+                    Scale::EqualTemperament,
+                    Scale::EqualTemperament.name(),
+                );
+                ui.selectable_value(
+                    &mut self.tuning.scale,
+                    Scale::JustIntonation,
+                    Scale::JustIntonation.name(),
+                );
+                if ui.selectable_label(false, "load scala file...").clicked() {
+                    self.load_scala();
+                }
+            });
+        ui.add(
+            egui::DragValue::new(&mut self.tuning.bend_range)
+                .clamp_range(1.0..=48.0)
+                .prefix("bend range ")
+                .suffix(" semitones"),
+        );
+    }
+
+    /// Starts or stops playback, telling devices that follow our clock.
+    fn set_playing(&mut self, playing: bool) {
+        self.playing = playing;
+        self.compile_code();
+        self.clock.reset();
+        if playing {
+            self.snapshot = Some(self.automaton.snapshot());
+            if self.sync == SyncMode::Lead {
+                self.midi_clock.reset();
+                self.send_clock(ClockMessage::Start);
+            }
+        } else {
+            self.stop_notes();
+            if self.sync == SyncMode::Lead {
+                self.send_clock(ClockMessage::Stop);
+            }
+        }
+    }
+
+    /// Plays along with the MIDI clock of another device.
+    fn follow_clock(&mut self, message: ClockMessage) {
+        match message {
+            ClockMessage::Clock if self.playing => {
+                for _ in 0..self.midi_clock.tick(&self.tempo) {
+                    self.step();
+                }
+            }
+            ClockMessage::Clock => (),
+            ClockMessage::Start => {
+                // Starting from the top replays the run from where playback
+                // first started.
+                if let Some(snapshot) = &self.snapshot {
+                    self.automaton.restore(snapshot);
+                }
+                self.midi_clock.reset();
+                self.set_playing(true);
+            }
+            ClockMessage::Continue => {
+                self.playing = true;
+                if self.snapshot.is_none() {
+                    self.snapshot = Some(self.automaton.snapshot());
+                }
+            }
+            ClockMessage::Stop => self.set_playing(false),
+            ClockMessage::SongPosition(position) => self.midi_clock.set_song_position(position),
+        }
+    }
+
+    fn send_clock(&mut self, message: ClockMessage) {
+        if let Some(midi_out) = &mut self.midi_out {
+            midi_out.send(&message.to_midi());
+        }
+    }
+
+    /// The settings of the built-in synth used by "export wav" and for
+    /// playing live.
+    fn synth_ui(&mut self, ui: &mut egui::Ui) {
+        ui.label("synth");
+        #[cfg(all(feature = "audio-out", target_os = "linux"))]
+        {
+            let mut live = self.audio_out.is_some();
+            if ui.checkbox(&mut live, "play live").changed() {
+                self.stop_notes();
+                self.audio_out = None;
+                if live {
+                    match cellgraph::synth::AudioOutput::connect(
+                        self.synth.clone(),
+                        self.tuning.bend_range,
+                    ) {
+                        Ok(output) => self.audio_out = Some(output),
+                        Err(error) => println!("unable to open audio output: {error}"),
+                    }
+                }
+            }
+        }
+        egui::ComboBox::from_id_source("waveform")
+            .selected_text(format!("{:?}", self.synth.waveform))
+            .show_ui(ui, |ui| {
+                for waveform in Waveform::ALL {
+                    ui.selectable_value(
+                        &mut self.synth.waveform,
+                        waveform,
+                        format!("{waveform:?}"),
+                    );
+                }
+            });
+        let envelope = &mut self.synth.envelope;
+        Grid::new("envelope grid").show(ui, |ui| {
+            for (label, value) in [
+                ("attack", &mut envelope.attack),
+                ("decay", &mut envelope.decay),
+                ("release", &mut envelope.release),
+            ] {
+                ui.label(label);
+                ui.add(
+                    egui::DragValue::new(value)
+                        .speed(0.01)
+                        .clamp_range(0.0..=10.0)
+                        .suffix(" s"),
+                );
+                ui.end_row();
+            }
+            ui.label("sustain");
+            ui.add(
+                egui::DragValue::new(&mut envelope.sustain)
+                    .speed(0.01)
+                    .clamp_range(0.0..=1.0),
+            );
+            ui.end_row();
+            ui.label("volume");
+            ui.add(
+                egui::DragValue::new(&mut self.synth.volume)
+                    .speed(0.01)
+                    .clamp_range(0.0..=1.0),
+            );
+            ui.end_row();
+        });
+    }
+
+    fn osc_ui(&mut self, ui: &mut egui::Ui) {
+        let mut sending = self.osc.is_some();
+        if ui.checkbox(&mut sending, "send osc").changed() {
+            self.osc = None;
+            if sending {
+                match OscSender::connect(self.osc_settings.clone()) {
+                    Ok(sender) => self.osc = Some(sender),
+                    Err(error) => println!("{error}"),
+                }
+            }
+        }
+        Grid::new("osc grid").show(ui, |ui| {
+            for (label, value) in [
+                ("target", &mut self.osc_settings.target),
+                ("node", &mut self.osc_settings.node_address),
+                ("step", &mut self.osc_settings.step_address),
+            ] {
+                ui.label(label);
+                ui.add_enabled(
+                    self.osc.is_none(),
+                    TextEdit::singleline(value).desired_width(120.0),
+                );
+                ui.end_row();
+            }
+        });
+    }
+
+    fn step(&mut self) {
+        let changes = self.automaton.step();
+        if let Some(osc) = &mut self.osc {
+            if let Err(error) = osc.send_step(&changes, &self.automaton.graph, &self.tuning) {
+                println!("unable to send osc message: {error}");
+            }
+        }
+        if let Some(midi_out) = &mut self.midi_out {
+            self.player
+                .play(&changes, &self.automaton, &self.tuning, midi_out.as_mut());
+            if let Some(error) = midi_out.take_error() {
+                println!("unable to send midi message: {error}");
+            }
+        }
+        #[cfg(all(feature = "audio-out", target_os = "linux"))]
+        if let Some(audio_out) = &mut self.audio_out {
+            audio_out.update(&self.synth, self.tuning.bend_range);
+            self.audio_player
+                .play(&changes, &self.automaton, &self.tuning, audio_out);
+            if let Some(error) = audio_out.take_error() {
+                println!("unable to play audio: {error}");
+                self.audio_out = None;
+                self.audio_player = Player::new();
+            }
+        }
+    }
+
+    /// Compiles the code and remembers the states to reset to, unless they
+    /// are already remembered, before stepping by hand.
+    fn start_stepping(&mut self) {
+        self.compile_code();
+        if self.snapshot.is_none() {
+            self.snapshot = Some(self.automaton.snapshot());
+        }
+    }
+
+    fn compile_code(&mut self) {
+        let (rules, diagnostics) = cellang::compile(&self.code);
+        self.automaton.rules = rules;
+        self.diagnostics = diagnostics;
+    }
+
+    /// Ends every note that is still sounding.
+    fn stop_notes(&mut self) {
+        if let Some(midi_out) = &mut self.midi_out {
+            self.player.stop(midi_out.as_mut());
+        }
+        #[cfg(all(feature = "audio-out", target_os = "linux"))]
+        if let Some(audio_out) = &mut self.audio_out {
+            self.audio_player.stop(audio_out);
+        }
+    }
+
+    fn connect_midi(&mut self) {
+        self.stop_notes();
+        self.midi_out = None;
+        #[cfg(feature = "midi-port")]
+        if !self.midi_port.is_empty() {
+            match cellgraph::midi::MidiPort::connect(Some(&self.midi_port)) {
+                Ok(port) => self.midi_out = Some(Box::new(port)),
+                Err(error) => println!("unable to connect to midi port: {error}"),
+            }
+        }
+    }
+
+    fn connect_midi_input(&mut self) {
+        self.midi_in = None;
+        self.note_input = NoteInput::new();
+        #[cfg(feature = "midi-port")]
+        if !self.midi_input_port.is_empty() {
+            match cellgraph::midi::MidiInputPort::connect(&self.midi_input_port) {
+                Ok(port) => self.midi_in = Some(Box::new(port)),
+                Err(error) => println!("unable to connect to midi port: {error}"),
+            }
+        }
+    }
+
     fn save_code(&self) {
         match rfd::FileDialog::new().save_file() {
             Some(file_path) => match File::create(file_path) {
@@ -446,6 +1039,22 @@ impl App {
         }
     }
 
+    fn load_scala(&mut self) {
+        match rfd::FileDialog::new()
+            .add_filter("scala", &["scl"])
+            .pick_file()
+        {
+            Some(file_path) => match fs::read_to_string(file_path) {
+                Ok(text) => match Scale::parse_scala(&text) {
+                    Ok(scale) => self.tuning.scale = scale,
+                    Err(error) => println!("unable to read scale: {error}"),
+                },
+                Err(error) => println!("{error}"),
+            },
+            None => println!("no file picked"),
+        }
+    }
+
     fn save_graph(&self) {
         match rfd::FileDialog::new().save_file() {
             Some(file_path) => match File::create(file_path) {
@@ -453,6 +1062,10 @@ impl App {
                     if let Ok(serialized) = serde_json::to_string(&SavedState {
                         automaton: self.automaton.clone(),
                         code: self.code.clone(),
+                        tempo: self.tempo,
+                        tuning: self.tuning.clone(),
+                        synth: self.synth.clone(),
+                        osc: self.osc_settings.clone(),
                     }) {
                         if let Err(error) = file.write_all(serialized.as_bytes()) {
                             println!("unable to write to file: {}", error)
@@ -465,13 +1078,69 @@ impl App {
         }
     }
 
+    fn export_midi(&self) {
+        match rfd::FileDialog::new()
+            .add_filter("midi", &["mid"])
+            .save_file()
+        {
+            Some(file_path) => match File::create(file_path) {
+                Ok(mut file) => {
+                    let data = smf::export(
+                        &self.automaton,
+                        self.export_steps,
+                        &self.tempo,
+                        &self.tuning,
+                    );
+                    if let Err(error) = file.write_all(&data) {
+                        println!("unable to write to file: {}", error)
+                    }
+                }
+                Err(err) => println!("unable to create file: {err}"),
+            },
+            _ => println!("no file chosen"),
+        }
+    }
+
+    fn export_wav(&self) {
+        match rfd::FileDialog::new()
+            .add_filter("wav", &["wav"])
+            .save_file()
+        {
+            Some(file_path) => match File::create(file_path) {
+                Ok(mut file) => {
+                    let data = wav::export(
+                        &self.automaton,
+                        self.export_steps,
+                        &self.tempo,
+                        &self.tuning,
+                        &self.synth,
+                    );
+                    if let Err(error) = file.write_all(&data) {
+                        println!("unable to write to file: {}", error)
+                    }
+                }
+                Err(err) => println!("unable to create file: {err}"),
+            },
+            _ => println!("no file chosen"),
+        }
+    }
+
     fn load_graph(&mut self) {
         match rfd::FileDialog::new().pick_file() {
             Some(file_path) => match fs::read_to_string(file_path) {
                 Ok(serialized) => match serde_json::from_str::<SavedState>(&serialized) {
                     Ok(state) => {
                         self.automaton = state.automaton;
-                        self.code = state.code
+                        self.code = state.code;
+                        self.tempo = state.tempo;
+                        self.reference_note = state.tuning.reference.to_string();
+                        self.tuning = state.tuning;
+                        self.synth = state.synth;
+                        self.osc_settings = state.osc;
+                        self.osc = None;
+                        self.selected = vec![];
+                        self.history.clear();
+                        self.compile_code();
                     }
                     Err(error) => println!("{error}"),
                 },
@@ -482,6 +1151,24 @@ impl App {
     }
 }
 
+fn update_mode_name(mode: UpdateMode) -> &'static str {
+    match mode {
+        UpdateMode::Synchronous => "synchronous",
+        UpdateMode::Sequential => "sequential",
+        UpdateMode::RandomOrder => "random order",
+        UpdateMode::RandomNode => "random node",
+        UpdateMode::Blocks => "update groups",
+    }
+}
+
+fn sync_name(sync: SyncMode) -> &'static str {
+    match sync {
+        SyncMode::Internal => "internal clock",
+        SyncMode::Follow => "follow midi clock",
+        SyncMode::Lead => "send midi clock",
+    }
+}
+
 fn find_rect(corner_1: Vector2, corner_2: Vector2) -> Rect {
     Rect {
         x: corner_1.x.min(corner_2.x),
@@ -1,46 +1,355 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 
-use crate::graph::{Graph, Node};
+use crate::{
+    graph::{Edge, Graph, Node, NodeId, State},
+    rng::Rng,
+};
 
 #[derive(serde::Serialize, serde::Deserialize, Clone)]
 pub struct Automaton {
+    /// Compiled from the project's code, so they aren't saved.
+    #[serde(skip)]
     pub rules: HashMap<String, Ruleset>,
     pub graph: Graph,
+    /// The seed `rng` was last seeded with.
+    #[serde(default)]
+    pub seed: u64,
+    #[serde(default)]
+    rng: Rng,
+    #[serde(default)]
+    pub update: UpdateMode,
+    /// Not saved, so delayed inputs of a loaded project start off.
+    #[serde(skip)]
+    history: StateHistory,
+}
+
+/// The order nodes are updated in during a step.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+pub enum UpdateMode {
+    /// Every node is updated from the states of the previous generation.
+    #[default]
+    Synchronous,
+    /// Nodes are updated one at a time in id order, each seeing the new states
+    /// of the nodes before it.
+    Sequential,
+    /// Like `Sequential`, but in a new random order every step.
+    RandomOrder,
+    /// A single node, picked at random, is updated each step.
+    RandomNode,
+    /// The nodes of each update group are updated together, group after group
+    /// in ascending order, each group seeing the new states of the groups
+    /// before it.
+    Blocks,
+}
+
+impl UpdateMode {
+    pub const ALL: [UpdateMode; 5] = [
+        UpdateMode::Synchronous,
+        UpdateMode::Sequential,
+        UpdateMode::RandomOrder,
+        UpdateMode::RandomNode,
+        UpdateMode::Blocks,
+    ];
 }
 
 impl Automaton {
     pub fn new(rules: HashMap<String, Ruleset>, graph: Graph) -> Self {
-        Self { rules, graph }
+        Self {
+            rules,
+            graph,
+            seed: 0,
+            rng: Rng::new(0),
+            update: UpdateMode::Synchronous,
+            history: StateHistory::default(),
+        }
+    }
+
+    /// Restarts the random number generator used by `random` and `chance`.
+    pub fn reseed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.rng = Rng::new(seed);
+    }
+
+    /// The past states delayed edges read from.
+    pub fn history(&self) -> &StateHistory {
+        &self.history
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            states: self
+                .graph
+                .nodes
+                .iter()
+                .map(|(id, node)| (*id, (node.read, node.write)))
+                .collect(),
+            rng: self.rng.clone(),
+            history: self.history.clone(),
+        }
     }
-    pub fn step(&mut self) {
-        for node in self.graph.nodes.iter_mut() {
-            std::mem::swap(&mut node.read, &mut node.write);
+
+    /// Puts the nodes back into the states they had in `snapshot`. Nodes added
+    /// since then keep their current state.
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        for (id, (read, write)) in &snapshot.states {
+            if let Some(node) = self.graph.nodes.get_mut(id) {
+                node.read = *read;
+                node.write = *write;
+            }
         }
+        self.rng = snapshot.rng.clone();
+        self.history = snapshot.history.clone();
+    }
 
-        for node in 0..self.graph.nodes.len() {
-            if let Some(rule) = self.rules.get(&self.graph[node].ruleset) {
-                rule.apply(node, &mut self.graph);
-            } else {
-                println!("no rule found for '{}'", self.graph[node].ruleset)
+    /// Steps until the states of all nodes repeat, or until `limit` steps have
+    /// been taken.
+    ///
+    /// The random number generator has to repeat along with the states, as
+    /// the steps after a repeat could otherwise differ. Rules and update modes
+    /// that draw random numbers every step therefore run up to the limit.
+    pub fn run_until_cycle(&mut self, limit: usize) -> RunOutcome {
+        let mut seen = HashMap::from([((self.recent_states(), self.rng.clone()), 0)]);
+        for steps in 1..=limit {
+            self.step();
+            let key = (self.recent_states(), self.rng.clone());
+            if let Some(previous) = seen.insert(key, steps) {
+                return match steps - previous {
+                    1 => RunOutcome::Stable { steps: previous },
+                    period => RunOutcome::Cycle { steps, period },
+                };
             }
         }
+        RunOutcome::Limit
+    }
+
+    /// Advances the automaton by one generation and returns the nodes whose
+    /// state changed.
+    pub fn step(&mut self) -> Vec<StateChange> {
+        self.history.record(&self.graph, self.max_delay());
+        // Nodes that aren't updated, like ones without a rule, keep their
+        // state.
+        for node in self.graph.nodes.values_mut() {
+            node.read = node.write;
+        }
+
+        let batches = self.batches();
+        if self.update == UpdateMode::Synchronous {
+            for node in batches.iter().flatten() {
+                self.apply(*node);
+            }
+        } else {
+            // Nodes see the states set earlier in the step through `read`, so
+            // it is moved along with each batch and put back at the end.
+            let previous: Vec<(NodeId, State)> = self
+                .graph
+                .nodes
+                .iter()
+                .map(|(id, node)| (*id, node.read))
+                .collect();
+            for batch in &batches {
+                for node in batch {
+                    self.apply(*node);
+                }
+                for node in batch {
+                    let node = &mut self.graph[*node];
+                    node.read = node.write;
+                }
+            }
+            for (id, state) in previous {
+                self.graph[id].read = state;
+            }
+        }
+
+        self.graph
+            .nodes
+            .iter()
+            .filter(|(_, node)| node.read != node.write)
+            .map(|(id, node)| StateChange {
+                node: *id,
+                previous: node.read,
+                state: node.write,
+            })
+            .collect()
+    }
+
+    /// The longest delay of any edge.
+    fn max_delay(&self) -> usize {
+        self.graph
+            .nodes
+            .values()
+            .flat_map(|node| &node.edges)
+            .map(|edge| edge.delay)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// The states of every node that the following steps depend on: the
+    /// latest ones and, for nodes with delayed edges out of them, as many
+    /// before them as those delays reach.
+    fn recent_states(&self) -> Vec<State> {
+        let mut depths: BTreeMap<NodeId, usize> = BTreeMap::new();
+        for edge in self.graph.nodes.values().flat_map(|node| &node.edges) {
+            let depth = depths.entry(edge.source).or_default();
+            *depth = (*depth).max(edge.delay);
+        }
+        let mut states = vec![];
+        for (id, node) in &self.graph.nodes {
+            states.push(node.write);
+            let depth = depths.get(id).copied().unwrap_or(0);
+            if depth > 0 {
+                states.push(node.read);
+                states.extend((1..depth).map(|delay| self.history.state(*id, delay)));
+            }
+        }
+        states
+    }
+
+    /// The nodes to update this step, in groups that are updated together.
+    fn batches(&mut self) -> Vec<Vec<NodeId>> {
+        let mut ids: Vec<NodeId> = self.graph.nodes.keys().copied().collect();
+        match self.update {
+            UpdateMode::Synchronous => vec![ids],
+            UpdateMode::Sequential => ids.into_iter().map(|id| vec![id]).collect(),
+            UpdateMode::RandomOrder => {
+                self.rng.shuffle(&mut ids);
+                ids.into_iter().map(|id| vec![id]).collect()
+            }
+            UpdateMode::RandomNode if ids.is_empty() => vec![],
+            UpdateMode::RandomNode => {
+                let picked = self.rng.range(0, ids.len() as i32) as usize;
+                vec![vec![ids[picked]]]
+            }
+            UpdateMode::Blocks => {
+                let mut groups: BTreeMap<usize, Vec<NodeId>> = BTreeMap::new();
+                for (id, node) in &self.graph.nodes {
+                    groups.entry(node.group).or_default().push(*id);
+                }
+                groups.into_values().collect()
+            }
+        }
+    }
+
+    fn apply(&mut self, node: NodeId) {
+        if let Some(rule) = self.rules.get(&self.graph[node].ruleset) {
+            rule.apply(node, &mut self.graph, &self.history, &mut self.rng);
+        }
+    }
+}
+
+/// The state of every node, and of the random number generator, at some point
+/// of a run.
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    states: BTreeMap<NodeId, (State, State)>,
+    rng: Rng,
+    history: StateHistory,
+}
+
+/// The states each node had before its current `read` state, most recent
+/// first, kept as far back as the longest edge delay reaches.
+#[derive(Clone, Debug, Default)]
+pub struct StateHistory {
+    states: BTreeMap<NodeId, VecDeque<State>>,
+}
+
+impl StateHistory {
+    /// The state of the source of `edge` as seen through the edge.
+    pub fn input(&self, graph: &Graph, edge: &Edge) -> State {
+        match edge.delay {
+            0 => graph[edge.source].read,
+            delay => self.state(edge.source, delay),
+        }
+    }
+
+    /// The state `node` had `delay` generations before its `read` state.
+    /// States from before the history begins are off.
+    fn state(&self, node: NodeId, delay: usize) -> State {
+        self.states
+            .get(&node)
+            .and_then(|states| states.get(delay - 1))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Remembers the `read` state of every node, keeping the last `depth`.
+    fn record(&mut self, graph: &Graph, depth: usize) {
+        if depth == 0 {
+            self.states.clear();
+            return;
+        }
+        self.states.retain(|id, _| graph.nodes.contains_key(id));
+        for (id, node) in &graph.nodes {
+            let states = self.states.entry(*id).or_default();
+            states.push_front(node.read);
+            states.truncate(depth);
+        }
+    }
+}
+
+/// How [`Automaton::run_until_cycle`] ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunOutcome {
+    /// The states stopped changing after `steps` steps.
+    Stable { steps: usize },
+    /// After `steps` steps the states were the same as `period` steps before.
+    Cycle { steps: usize, period: usize },
+    /// The states didn't repeat within the step limit.
+    Limit,
+}
+
+/// A node that changed state during a step.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StateChange {
+    pub node: NodeId,
+    pub previous: State,
+    pub state: State,
+}
+
+impl StateChange {
+    /// Whether the node went from off (state 0) to any other state.
+    pub fn turned_on(&self) -> bool {
+        self.previous == 0 && self.state != 0
+    }
+
+    /// Whether the node went from any other state to off (state 0).
+    pub fn turned_off(&self) -> bool {
+        self.previous != 0 && self.state == 0
     }
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct Ruleset {
-    pub pattern: BoolPattern,
-    pub case: bool,
+    /// Cells using this ruleset have a state in `0..states`.
+    pub states: State,
+    /// Tried in order; the first case whose pattern matches sets the new
+    /// state.
+    pub cases: Vec<Case>,
+    /// The new state when no case matches. `None` keeps the current state.
+    pub otherwise: Option<IntExpr>,
     pub name: String,
 }
 
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Case {
+    pub pattern: BoolPattern,
+    pub result: IntExpr,
+}
+
 impl Ruleset {
-    fn apply(&self, node: usize, graph: &mut Graph) {
-        graph.nodes[node].write = if self.pattern.calculate(&graph.nodes[node], graph) {
-            self.case
-        } else {
-            !self.case
-        }
+    fn apply(&self, node: NodeId, graph: &mut Graph, history: &StateHistory, rng: &mut Rng) {
+        let result = self
+            .cases
+            .iter()
+            .find(|case| case.pattern.calculate(node, graph, history, rng))
+            .map(|case| &case.result)
+            .or(self.otherwise.as_ref());
+        graph[node].write = match result {
+            Some(result) => {
+                let state = result.calculate(node, graph, history, rng);
+                state.rem_euclid(self.states.max(1) as i32) as State
+            }
+            None => graph[node].read,
+        };
     }
 }
 
@@ -52,63 +361,516 @@ pub enum BoolPattern {
     Equal(IntExpr, IntExpr),
     Gth(IntExpr, IntExpr),
     Lth(IntExpr, IntExpr),
+    /// True with the given probability.
+    Chance(f64),
     MyValue,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub enum IntExpr {
-    On,
-    Off,
-    In,
+    /// The number of inputs that are on, over the edges of the given class
+    /// or over all edges.
+    On(Option<String>),
+    Off(Option<String>),
+    In(Option<String>),
     Lit(i32),
     Add(Box<IntExpr>, Box<IntExpr>),
     Sub(Box<IntExpr>, Box<IntExpr>),
     Mul(Box<IntExpr>, Box<IntExpr>),
+    /// Gives 0 when dividing by 0, like `Mod`.
     Div(Box<IntExpr>, Box<IntExpr>),
     Mod(Box<IntExpr>, Box<IntExpr>),
-}
-
-struct Statement {
-    replacement: bool,
-    pattern: BoolPattern,
+    /// The number of inputs in the given state, over the edges of the given
+    /// class or over all edges.
+    Count(Box<IntExpr>, Option<String>),
+    /// The node's own state.
+    State,
+    /// The number of nodes this node has an edge into, over the edges of the
+    /// given class or over all edges.
+    Out(Option<String>),
+    /// The number of those nodes that are on.
+    OutOn(Option<String>),
+    OutOff(Option<String>),
+    /// The sum of the weights of the edges from inputs that are on, over the
+    /// edges of the given class or over all edges.
+    WeightedSum(Option<String>),
+    /// A uniformly distributed integer from the first value up to, but not
+    /// including, the second.
+    Random(Box<IntExpr>, Box<IntExpr>),
 }
 
 impl BoolPattern {
-    fn calculate(&self, node: &Node, graph: &Graph) -> bool {
+    fn calculate(&self, id: NodeId, graph: &Graph, history: &StateHistory, rng: &mut Rng) -> bool {
         match self {
             BoolPattern::Or(left, right) => {
-                left.calculate(node, graph) || right.calculate(node, graph)
+                left.calculate(id, graph, history, rng) || right.calculate(id, graph, history, rng)
             }
             BoolPattern::And(left, right) => {
-                left.calculate(node, graph) && right.calculate(node, graph)
+                left.calculate(id, graph, history, rng) && right.calculate(id, graph, history, rng)
             }
-            BoolPattern::Not(left) => !left.calculate(node, graph),
+            BoolPattern::Not(left) => !left.calculate(id, graph, history, rng),
             BoolPattern::Equal(left, right) => {
-                left.calculate(node, graph) == right.calculate(node, graph)
+                left.calculate(id, graph, history, rng) == right.calculate(id, graph, history, rng)
             }
             BoolPattern::Gth(left, right) => {
-                left.calculate(node, graph) > right.calculate(node, graph)
+                left.calculate(id, graph, history, rng) > right.calculate(id, graph, history, rng)
             }
             BoolPattern::Lth(left, right) => {
-                left.calculate(node, graph) < right.calculate(node, graph)
+                left.calculate(id, graph, history, rng) < right.calculate(id, graph, history, rng)
             }
-            BoolPattern::MyValue => node.read,
+            BoolPattern::Chance(probability) => rng.chance(*probability),
+            BoolPattern::MyValue => graph[id].read != 0,
         }
     }
 }
 
 impl IntExpr {
-    fn calculate(&self, node: &Node, graph: &Graph) -> i32 {
+    pub(crate) fn calculate(
+        &self,
+        id: NodeId,
+        graph: &Graph,
+        history: &StateHistory,
+        rng: &mut Rng,
+    ) -> i32 {
+        let node = &graph[id];
         match self {
-            IntExpr::On => node.edges.iter().filter(|a| graph[**a].read).count() as i32,
-            IntExpr::Off => node.edges.iter().filter(|a| !graph.nodes[**a].read).count() as i32,
-            IntExpr::In => node.edges.len() as i32,
+            IntExpr::On(class) => edges_in(node, class)
+                .filter(|edge| history.input(graph, edge) != 0)
+                .count() as i32,
+            IntExpr::Off(class) => edges_in(node, class)
+                .filter(|edge| history.input(graph, edge) == 0)
+                .count() as i32,
+            IntExpr::In(class) => edges_in(node, class).count() as i32,
+            IntExpr::WeightedSum(class) => edges_in(node, class)
+                .filter(|edge| history.input(graph, edge) != 0)
+                .map(|edge| edge.weight)
+                .fold(0, i32::saturating_add),
             IntExpr::Lit(num) => *num,
-            IntExpr::Add(left, right) => left.calculate(node, graph) + right.calculate(node, graph),
-            IntExpr::Sub(left, right) => left.calculate(node, graph) - right.calculate(node, graph),
-            IntExpr::Mul(left, right) => left.calculate(node, graph) * right.calculate(node, graph),
-            IntExpr::Div(left, right) => left.calculate(node, graph) / right.calculate(node, graph),
-            IntExpr::Mod(left, right) => left.calculate(node, graph) % right.calculate(node, graph),
+            IntExpr::Add(left, right) => {
+                let left = left.calculate(id, graph, history, rng);
+                left.saturating_add(right.calculate(id, graph, history, rng))
+            }
+            IntExpr::Sub(left, right) => {
+                let left = left.calculate(id, graph, history, rng);
+                left.saturating_sub(right.calculate(id, graph, history, rng))
+            }
+            IntExpr::Mul(left, right) => {
+                let left = left.calculate(id, graph, history, rng);
+                left.saturating_mul(right.calculate(id, graph, history, rng))
+            }
+            IntExpr::Div(left, right) => {
+                let left = left.calculate(id, graph, history, rng);
+                left.checked_div(right.calculate(id, graph, history, rng))
+                    .unwrap_or(0)
+            }
+            IntExpr::Mod(left, right) => {
+                let left = left.calculate(id, graph, history, rng);
+                left.checked_rem(right.calculate(id, graph, history, rng))
+                    .unwrap_or(0)
+            }
+            IntExpr::Count(state, class) => {
+                let state = state.calculate(id, graph, history, rng);
+                edges_in(node, class)
+                    .filter(|edge| history.input(graph, edge) as i32 == state)
+                    .count() as i32
+            }
+            IntExpr::State => node.read as i32,
+            IntExpr::Out(class) => outputs_in(graph, id, class).count() as i32,
+            IntExpr::OutOn(class) => outputs_in(graph, id, class)
+                .filter(|target| graph[*target].read != 0)
+                .count() as i32,
+            IntExpr::OutOff(class) => outputs_in(graph, id, class)
+                .filter(|target| graph[*target].read == 0)
+                .count() as i32,
+            IntExpr::Random(low, high) => {
+                let low = low.calculate(id, graph, history, rng);
+                let high = high.calculate(id, graph, history, rng);
+                rng.range(low, high)
+            }
+        }
+    }
+}
+
+/// The edges into `node` of `class`, or all of them.
+fn edges_in<'a>(node: &'a Node, class: &'a Option<String>) -> impl Iterator<Item = &'a Edge> {
+    node.edges
+        .iter()
+        .filter(move |edge| edge.is_in(class.as_deref()))
+}
+
+/// The nodes `id` has an edge of `class` into, or an edge of any class into.
+fn outputs_in<'a>(
+    graph: &'a Graph,
+    id: NodeId,
+    class: &'a Option<String>,
+) -> impl Iterator<Item = NodeId> + 'a {
+    graph.outputs(id).filter(move |target| {
+        graph
+            .edge(*target, id)
+            .is_some_and(|edge| edge.is_in(class.as_deref()))
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{cellang, vec2::Vector2};
+
+    /// An automaton running `code` with a node for each `(state, ruleset,
+    /// sources)`, where sources are the indices of earlier or later nodes.
+    /// Shared by the tests of every module that needs a running graph.
+    pub(crate) fn automaton(code: &str, nodes: &[(State, &str, &[usize])]) -> Automaton {
+        let mut graph = Graph::new();
+        for (state, ruleset, _) in nodes {
+            graph.add_node(Node::new(
+                0,
+                *state,
+                vec![],
+                Vector2::zero(),
+                ruleset.to_string(),
+            ));
+        }
+        for (id, (_, _, sources)) in nodes.iter().enumerate() {
+            for source in *sources {
+                graph.add_edge(NodeId(id), NodeId(*source));
+            }
+        }
+        let (rules, diagnostics) = cellang::compile(code);
+        assert!(diagnostics.is_empty(), "{diagnostics:?}");
+        Automaton::new(rules, graph)
+    }
+
+    fn states(automaton: &Automaton) -> Vec<State> {
+        automaton
+            .graph
+            .nodes
+            .values()
+            .map(|node| node.write)
+            .collect()
+    }
+
+    /// The state a node running `expression` on its own gets.
+    fn result(expression: &str) -> State {
+        let code = format!("calc[200] state >= 0: {expression}");
+        let mut automaton = automaton(&code, &[(0, "calc", &[])]);
+        automaton.step();
+        automaton.graph[NodeId(0)].write
+    }
+
+    #[test]
+    fn division_by_zero_gives_zero() {
+        assert_eq!(result("7 / off + 1"), 1);
+        assert_eq!(result("7 % (state * 5) + 1"), 1);
+        assert_eq!(result("7 / 2"), 3);
+    }
+
+    #[test]
+    fn arithmetic_saturates() {
+        assert_eq!(result("(2000000000 + 2000000000) % 200"), 47);
+        assert_eq!(result("(2000000000 * 3) % 200"), 47);
+        assert_eq!(result("(0 - 2000000000 - 2000000000) % 200"), 152);
+    }
+
+    #[test]
+    fn run_until_cycle_finds_stable_states() {
+        // The on state spreads along the chain and then stays.
+        let mut automaton = automaton(
+            "keep on > 0 | state = 1: on",
+            &[(1, "keep", &[]), (0, "keep", &[0]), (0, "keep", &[1])],
+        );
+        assert_eq!(
+            automaton.run_until_cycle(100),
+            RunOutcome::Stable { steps: 2 }
+        );
+        assert_eq!(states(&automaton), [1, 1, 1]);
+    }
+
+    #[test]
+    fn run_until_cycle_finds_a_blinker() {
+        let mut automaton = automaton("blink state = 0: on", &[(0, "blink", &[])]);
+        assert_eq!(
+            automaton.run_until_cycle(100),
+            RunOutcome::Cycle {
+                steps: 2,
+                period: 2
+            }
+        );
+    }
+
+    #[test]
+    fn run_until_cycle_stops_at_the_limit() {
+        let mut automaton = automaton("up[200] state >= 0: state + 1", &[(0, "up", &[])]);
+        assert_eq!(automaton.run_until_cycle(50), RunOutcome::Limit);
+        assert_eq!(states(&automaton), [50]);
+    }
+
+    #[test]
+    fn run_until_cycle_does_not_take_chance_repeats_for_cycles() {
+        let mut coin = automaton("coin random(0, 2) = 1: on", &[(0, "coin", &[])]);
+        assert_eq!(coin.run_until_cycle(200), RunOutcome::Limit);
+
+        let mut automaton = automaton("keep state = 1: on", &[(1, "keep", &[]), (0, "keep", &[])]);
+        automaton.update = UpdateMode::RandomNode;
+        assert_eq!(automaton.run_until_cycle(200), RunOutcome::Limit);
+    }
+
+    /// A node that is on only in the first generation, copied by one node
+    /// through an edge with a delay of 2 and by another through an edge
+    /// without delay.
+    fn delayed_pulse() -> Automaton {
+        let mut automaton = automaton(
+            "pulse state < 0: on\ncopy on > 0: on",
+            &[(1, "pulse", &[]), (0, "copy", &[0]), (0, "copy", &[0])],
+        );
+        automaton
+            .graph
+            .edge_mut(NodeId(1), NodeId(0))
+            .unwrap()
+            .delay = 2;
+        automaton
+    }
+
+    /// Steps `automaton` `steps` times and returns the states after each
+    /// step.
+    fn run(automaton: &mut Automaton, steps: usize) -> Vec<Vec<State>> {
+        (0..steps)
+            .map(|_| {
+                automaton.step();
+                states(automaton)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn delayed_edges_deliver_states_late() {
+        let mut automaton = delayed_pulse();
+        assert_eq!(
+            run(&mut automaton, 4),
+            [[0, 0, 1], [0, 0, 0], [0, 1, 0], [0, 0, 0]]
+        );
+    }
+
+    #[test]
+    fn snapshots_keep_delayed_states() {
+        let mut automaton = delayed_pulse();
+        automaton.step();
+        let snapshot = automaton.snapshot();
+        let after = run(&mut automaton, 3);
+        assert_eq!(after, [[0, 0, 0], [0, 1, 0], [0, 0, 0]]);
+
+        // Restoring in the middle of the run brings back the pulse still on
+        // its way through the delayed edge.
+        automaton.step();
+        automaton.restore(&snapshot);
+        assert_eq!(run(&mut automaton, 3), after);
+
+        // Restoring the start forgets the states of later generations.
+        let mut automaton = delayed_pulse();
+        let start = automaton.snapshot();
+        run(&mut automaton, 2);
+        automaton.restore(&start);
+        assert_eq!(
+            run(&mut automaton, 4),
+            [[0, 0, 1], [0, 0, 0], [0, 1, 0], [0, 0, 0]]
+        );
+    }
+
+    /// A node that turns on followed by a chain of `length` nodes that copy
+    /// the one before them, updated with `update`.
+    fn chain(length: usize, update: UpdateMode) -> Automaton {
+        let sources: Vec<[usize; 1]> = (0..length).map(|index| [index]).collect();
+        let mut nodes: Vec<(State, &str, &[usize])> = vec![(0, "source", &[])];
+        nodes.extend(sources.iter().map(|source| (0, "copy", &source[..])));
+        let mut automaton = automaton("source state >= 0: on\ncopy on > 0: on", &nodes);
+        automaton.update = update;
+        automaton
+    }
+
+    /// Nodes without inputs that count up each time they are updated.
+    fn counters(count: usize, update: UpdateMode, seed: u64) -> Automaton {
+        let nodes = vec![(0, "count", &[][..]); count];
+        let mut automaton = automaton("count[100] state >= 0: state + 1", &nodes);
+        automaton.update = update;
+        automaton.reseed(seed);
+        automaton
+    }
+
+    #[test]
+    fn synchronous_steps_move_one_hop() {
+        let mut automaton = chain(3, UpdateMode::Synchronous);
+        assert_eq!(
+            run(&mut automaton, 4),
+            [[1, 0, 0, 0], [1, 1, 0, 0], [1, 1, 1, 0], [1, 1, 1, 1]]
+        );
+    }
+
+    #[test]
+    fn sequential_steps_move_along_the_whole_chain() {
+        let mut automaton = chain(3, UpdateMode::Sequential);
+        assert_eq!(run(&mut automaton, 1), [[1, 1, 1, 1]]);
+    }
+
+    #[test]
+    fn random_order_updates_every_node_once_in_varying_order() {
+        let mut automaton = counters(5, UpdateMode::RandomOrder, 1);
+        assert_eq!(run(&mut automaton, 2), [[1; 5], [2; 5]]);
+
+        // How far the chain gets in one step depends on the order.
+        let reached: std::collections::BTreeSet<usize> = (0..50)
+            .map(|seed| {
+                let mut automaton = chain(4, UpdateMode::RandomOrder);
+                automaton.reseed(seed);
+                automaton.step();
+                states(&automaton)
+                    .iter()
+                    .filter(|state| **state == 1)
+                    .count()
+            })
+            .collect();
+        assert!(reached.len() > 1, "{reached:?}");
+    }
+
+    #[test]
+    fn random_node_updates_exactly_one_node() {
+        let mut automaton = counters(5, UpdateMode::RandomNode, 7);
+        for steps in 1..=20 {
+            automaton.step();
+            assert_eq!(states(&automaton).iter().sum::<State>(), steps);
         }
+        // The picks follow the seed.
+        let mut again = counters(5, UpdateMode::RandomNode, 7);
+        run(&mut again, 20);
+        assert_eq!(states(&again), states(&automaton));
+    }
+
+    #[test]
+    fn blocks_see_their_own_previous_states_and_earlier_blocks() {
+        // Node 0 in the first block, and the chain after it in the second.
+        let mut automaton = chain(2, UpdateMode::Blocks);
+        automaton.graph[NodeId(1)].group = 1;
+        automaton.graph[NodeId(2)].group = 1;
+        // Node 1 sees node 0 turn on in the same step, but node 2 only sees
+        // the state node 1 had before its block was updated.
+        assert_eq!(run(&mut automaton, 2), [[1, 1, 0], [1, 1, 1]]);
+
+        // In the same block the nodes are updated synchronously.
+        let mut automaton = chain(2, UpdateMode::Blocks);
+        assert_eq!(run(&mut automaton, 1), [[1, 0, 0]]);
+    }
+
+    /// A node adding 100 to `expression` over edges from a node that is on
+    /// with weight 3, one that is on with weight -5 and one that is off with
+    /// weight 7, in classes `a`, `b` and `a`.
+    fn weighted(expression: &str) -> Automaton {
+        let code = format!("calc[200] state >= 0: {expression} + 100");
+        let mut automaton = automaton(
+            &code,
+            &[
+                (1, "none", &[]),
+                (1, "none", &[]),
+                (0, "none", &[]),
+                (0, "calc", &[0, 1, 2]),
+            ],
+        );
+        for (source, weight, class) in [(0, 3, "a"), (1, -5, "b"), (2, 7, "a")] {
+            let edge = automaton.graph.edge_mut(NodeId(3), NodeId(source)).unwrap();
+            edge.weight = weight;
+            edge.class = class.to_string();
+        }
+        automaton
+    }
+
+    fn weighted_result(expression: &str) -> i32 {
+        let mut automaton = weighted(expression);
+        automaton.step();
+        automaton.graph[NodeId(3)].write as i32 - 100
+    }
+
+    #[test]
+    fn wsum_adds_the_weights_of_inputs_that_are_on() {
+        assert_eq!(weighted_result("wsum"), -2);
+        assert_eq!(weighted_result("wsum(a)"), 3);
+        assert_eq!(weighted_result("wsum(b)"), -5);
+        assert_eq!(weighted_result("wsum(c)"), 0);
+        assert_eq!(weighted_result("wsum(a) * 2 - wsum(b)"), 11);
+    }
+
+    #[test]
+    fn wsum_sees_delayed_inputs_late() {
+        let mut automaton = weighted("wsum");
+        automaton
+            .graph
+            .edge_mut(NodeId(3), NodeId(1))
+            .unwrap()
+            .delay = 2;
+        let sums: Vec<i32> = (0..4)
+            .map(|_| {
+                automaton.step();
+                automaton.graph[NodeId(3)].write as i32 - 100
+            })
+            .collect();
+        // The inhibiting input only arrives after two more generations.
+        assert_eq!(sums, [3, 3, -2, -2]);
+    }
+
+    #[test]
+    fn weights_are_whole_numbers() {
+        let edge: Edge = serde_json::from_str(r#"{"source": 0, "weight": -4}"#).unwrap();
+        assert_eq!(edge.weight, -4);
+        assert!(serde_json::from_str::<Edge>(r#"{"source": 0, "weight": 0.5}"#).is_err());
+    }
+
+    #[test]
+    fn counts_only_see_edges_of_their_class() {
+        for (expression, expected) in [
+            ("on", 2),
+            ("on(a)", 1),
+            ("on(b)", 1),
+            ("off(a)", 1),
+            ("off(b)", 0),
+            ("in", 3),
+            ("in(a)", 2),
+            ("in(b)", 1),
+            ("in(c)", 0),
+            ("count(1, a)", 1),
+            ("count(0, a)", 1),
+            ("count(1, b)", 1),
+            ("count(0, b)", 0),
+            ("count(0)", 1),
+        ] {
+            assert_eq!(weighted_result(expression), expected, "{expression}");
+        }
+    }
+
+    #[test]
+    fn output_counts_only_see_edges_of_their_class() {
+        // Node 0 leads into node 1, which is on, over an edge of class `a`,
+        // and into node 2, which is off, over one of class `b`.
+        let result = |expression: &str| {
+            let code = format!("calc[200] state >= 0: {expression} + 100");
+            let mut automaton = automaton(
+                &code,
+                &[(0, "calc", &[]), (1, "none", &[0]), (0, "none", &[0])],
+            );
+            automaton
+                .graph
+                .edge_mut(NodeId(1), NodeId(0))
+                .unwrap()
+                .class = "a".to_string();
+            automaton
+                .graph
+                .edge_mut(NodeId(2), NodeId(0))
+                .unwrap()
+                .class = "b".to_string();
+            automaton.step();
+            automaton.graph[NodeId(0)].write as i32 - 100
+        };
+        assert_eq!(result("out"), 2);
+        assert_eq!(result("out(a)"), 1);
+        assert_eq!(result("outon(a)"), 1);
+        assert_eq!(result("outoff(a)"), 0);
+        assert_eq!(result("outon(b)"), 0);
+        assert_eq!(result("outoff(b)"), 1);
+        assert_eq!(result("out(c)"), 0);
     }
 }
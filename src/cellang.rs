@@ -1,17 +1,43 @@
-use std::iter::Peekable;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+};
 
-use logos::{Lexer, Logos};
-
-use crate::automaton::{BoolPattern, IntExpr, Ruleset};
+use crate::{
+    automaton::{BoolPattern, Case, IntExpr, Ruleset},
+    graph::{Graph, NodeId, State},
+};
 peg::parser! {
     pub grammar expr_parser() for str {
+        rule class_name() -> String
+            = whitespace()? name:$(['a'..='z']+) whitespace()? {name.to_string()}
+        rule class() -> String
+            = whitespace()? "(" name:class_name() ")" {name}
         rule on() -> IntExpr
-            = whitespace()? "on" whitespace()? {IntExpr::On}
+            = whitespace()? "on" class:class()? whitespace()? {IntExpr::On(class)}
         rule off() -> IntExpr
-            = whitespace()? "off" whitespace()? {IntExpr::Off}
+            = whitespace()? "off" class:class()? whitespace()? {IntExpr::Off(class)}
         rule input() -> IntExpr
-            = whitespace()? "in" whitespace()? {IntExpr::In}
-        rule value() -> IntExpr = x:int() / x:on() / x:off() / x:input()  {
+            = whitespace()? "in" class:class()? whitespace()? {IntExpr::In(class)}
+        rule random() -> IntExpr
+            = whitespace()? "random" whitespace()? "(" low:arithmetic() "," high:arithmetic() ")" whitespace()? {
+                IntExpr::Random(Box::new(low), Box::new(high))
+            }
+        rule count() -> IntExpr
+            = whitespace()? "count" whitespace()? "(" state:arithmetic() class:("," c:class_name() {c})? ")" whitespace()? {
+                IntExpr::Count(Box::new(state), class)
+            }
+        rule state() -> IntExpr
+            = whitespace()? "state" whitespace()? {IntExpr::State}
+        rule out_on() -> IntExpr
+            = whitespace()? "outon" class:class()? whitespace()? {IntExpr::OutOn(class)}
+        rule out_off() -> IntExpr
+            = whitespace()? "outoff" class:class()? whitespace()? {IntExpr::OutOff(class)}
+        rule out() -> IntExpr
+            = whitespace()? "out" class:class()? whitespace()? {IntExpr::Out(class)}
+        rule weighted_sum() -> IntExpr
+            = whitespace()? "wsum" class:class()? whitespace()? {IntExpr::WeightedSum(class)}
+        rule value() -> IntExpr = x:int() / x:on() / x:off() / x:input() / x:random() / x:count() / x:state() / x:weighted_sum() / x:out_on() / x:out_off() / x:out()  {
             x
         }
         rule number() -> i32
@@ -19,6 +45,11 @@ peg::parser! {
         rule int() -> IntExpr =
             x:number() {IntExpr::Lit(x)}
 
+        rule probability() -> f64
+          = whitespace()? p:$(['0'..='9']+ ("." ['0'..='9']*)?) whitespace()? {? p.parse().or(Err("probability")) }
+        rule chance() -> BoolPattern
+            = whitespace()? "chance" whitespace()? "(" p:probability() ")" whitespace()? {BoolPattern::Chance(p)}
+
         rule whitespace() = quiet!{[' ' | '\t']+}
 
         pub rule arithmetic() -> IntExpr = precedence!{
@@ -30,7 +61,7 @@ peg::parser! {
           x:(@) "%" y:@ { IntExpr::Mod(Box::new(x), Box::new(y)) }
           --
           n:value() { n }
-          "(" e:arithmetic() ")" { e }
+          whitespace()? "(" e:arithmetic() ")" whitespace()? { e }
         }
 
         rule gth() -> BoolPattern =
@@ -62,17 +93,287 @@ peg::parser! {
             "!" x:(@) {BoolPattern::Not(Box::new(x))}
             --
             n:compare() {n}
-            "self" {BoolPattern::MyValue}
+            n:chance() {n}
+            whitespace()? "(" x:bools() ")" whitespace()? {x}
+            whitespace()? "self" whitespace()? {BoolPattern::MyValue}
         }
 
-        rule on_to_bool() -> bool =
-            "on" {true}
-        rule off_to_bool() -> bool =
-            "off" {false}
-        rule on_off_to_bool() -> bool =
-            x:on_to_bool() / x:off_to_bool() {x}
+        rule on_off() -> IntExpr =
+            "on" {IntExpr::Lit(1)} / "off" {IntExpr::Lit(0)}
+        rule result() -> IntExpr =
+            x:on_off() whitespace()? &("\n" / ![_]) {x} / x:arithmetic() {x}
+
+        rule states() -> State =
+            "[" n:number() "]" {? State::try_from(n).ok().filter(|n| *n > 0).ok_or("state count from 1 to 255") }
+
+        rule case() -> Case =
+            pattern:bools() whitespace()? ":" whitespace()? result:result() whitespace()? {Case { pattern, result }}
+        rule otherwise() -> IntExpr =
+            whitespace() "else" whitespace()? ":" whitespace()? result:result() whitespace()? {result}
 
         pub rule ruleset() -> Ruleset =
-            name:$(['a'..='z']+) whitespace() pattern:bools() whitespace()? ":" whitespace()? case: on_off_to_bool() whitespace()? {Ruleset { pattern: pattern, case, name: name.to_string() }}
+            name:$(['a'..='z']+) states:states()? whitespace() case:case() ![_] {
+                Ruleset {
+                    states: states.unwrap_or(2),
+                    otherwise: Some(complement(&case.result)),
+                    cases: vec![case],
+                    name: name.to_string(),
+                }
+            }
+            / name:$(['a'..='z']+) states:states()? whitespace()? cases:("\n" whitespace() c:case() {c})+ otherwise:("\n" o:otherwise() {o})? ![_] {
+                Ruleset {
+                    states: states.unwrap_or(2),
+                    cases,
+                    otherwise,
+                    name: name.to_string(),
+                }
+            }
+    }
+}
+
+/// The state a single-line rule falls back to when its pattern doesn't
+/// match: on when the rule turns cells off, off otherwise.
+fn complement(case: &IntExpr) -> IntExpr {
+    match case {
+        IntExpr::Lit(0) => IntExpr::Lit(1),
+        _ => IntExpr::Lit(0),
+    }
+}
+
+/// A problem found while compiling rules or checking them against a graph.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Diagnostic {
+    /// A line that doesn't parse. Lines and columns start at 1.
+    Syntax {
+        line: usize,
+        column: usize,
+        rule: Option<String>,
+        expected: Vec<String>,
+    },
+    /// A ruleset with the same name as one defined on an earlier line.
+    DuplicateRule {
+        line: usize,
+        rule: String,
+        first_line: usize,
+    },
+    /// An indented line below a rule whose case is already on its first line.
+    CaseAfterSingleLineRule { line: usize, rule: String },
+    /// Nodes that use a ruleset which isn't defined.
+    UndefinedRuleset { ruleset: String, nodes: Vec<NodeId> },
+}
+
+impl Diagnostic {
+    /// The line of the code the diagnostic points at, if any.
+    pub fn line(&self) -> Option<usize> {
+        match self {
+            Diagnostic::Syntax { line, .. }
+            | Diagnostic::DuplicateRule { line, .. }
+            | Diagnostic::CaseAfterSingleLineRule { line, .. } => Some(*line),
+            Diagnostic::UndefinedRuleset { .. } => None,
+        }
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Diagnostic::Syntax {
+                line,
+                column,
+                rule,
+                expected,
+            } => {
+                write!(f, "{line}:{column}: ")?;
+                if let Some(rule) = rule {
+                    write!(f, "in rule '{rule}': ")?;
+                }
+                write!(f, "expected one of {}", expected.join(", "))
+            }
+            Diagnostic::DuplicateRule {
+                line,
+                rule,
+                first_line,
+            } => write!(
+                f,
+                "{line}: rule '{rule}' is already defined on line {first_line}"
+            ),
+            Diagnostic::CaseAfterSingleLineRule { line, rule } => write!(
+                f,
+                "{line}: rule '{rule}' has its case on the line of its name, so it can't \
+                 have more cases; start the cases on the line below the name"
+            ),
+            Diagnostic::UndefinedRuleset { ruleset, nodes } => {
+                let nodes: Vec<String> = nodes.iter().map(|id| id.to_string()).collect();
+                write!(
+                    f,
+                    "ruleset '{ruleset}' is not defined but used by node {}",
+                    nodes.join(", ")
+                )
+            }
+        }
+    }
+}
+
+/// Parses the rulesets in `code`. A ruleset starts on an unindented line and
+/// continues over the indented lines below it. Rulesets that fail to parse
+/// and rulesets defined twice are reported instead of being added.
+pub fn compile(code: &str) -> (HashMap<String, Ruleset>, Vec<Diagnostic>) {
+    let mut rules = HashMap::new();
+    let mut defined_on = HashMap::new();
+    let mut diagnostics = vec![];
+
+    for block in blocks(code) {
+        let line = block[0].0;
+        let text: Vec<&str> = block.iter().map(|(_, text)| *text).collect();
+        match expr_parser::ruleset(&text.join("\n")) {
+            Ok(parsed) => {
+                if let Some(&first_line) = defined_on.get(&parsed.name) {
+                    diagnostics.push(Diagnostic::DuplicateRule {
+                        line,
+                        rule: parsed.name,
+                        first_line,
+                    });
+                } else {
+                    defined_on.insert(parsed.name.clone(), line);
+                    rules.insert(parsed.name.clone(), parsed);
+                }
+            }
+            // The first line is a whole rule on its own, so the indented lines
+            // below it are what's wrong, not the place the parser gave up.
+            Err(_) if block.len() > 1 && expr_parser::ruleset(text[0]).is_ok() => {
+                diagnostics.push(Diagnostic::CaseAfterSingleLineRule {
+                    line: block[1].0,
+                    rule: rule_name(text[0]),
+                })
+            }
+            Err(error) => {
+                let mut expected: Vec<String> = error
+                    .expected
+                    .tokens()
+                    .map(|token| token.to_string())
+                    .collect();
+                expected.sort();
+                diagnostics.push(Diagnostic::Syntax {
+                    line: block[error.location.line - 1].0,
+                    column: error.location.column,
+                    rule: Some(rule_name(text[0])).filter(|name| !name.is_empty()),
+                    expected,
+                })
+            }
+        }
+    }
+    (rules, diagnostics)
+}
+
+/// The name at the start of the first line of a rule.
+fn rule_name(line: &str) -> String {
+    line.chars()
+        .take_while(|c| c.is_ascii_lowercase())
+        .collect()
+}
+
+/// Groups the non-empty lines of `code` into rulesets, keeping their line
+/// numbers.
+fn blocks(code: &str) -> Vec<Vec<(usize, &str)>> {
+    let mut blocks: Vec<Vec<(usize, &str)>> = vec![];
+    for (i, text) in code.lines().enumerate() {
+        if text.trim().is_empty() {
+            continue;
+        }
+        let indented = text.starts_with([' ', '\t']);
+        match blocks.last_mut() {
+            Some(block) if indented => block.push((i + 1, text)),
+            _ => blocks.push(vec![(i + 1, text)]),
+        }
+    }
+    blocks
+}
+
+/// Reports the rulesets used by nodes of `graph` that aren't in `rules`.
+pub fn check_references(rules: &HashMap<String, Ruleset>, graph: &Graph) -> Vec<Diagnostic> {
+    let mut undefined: BTreeMap<&str, Vec<NodeId>> = BTreeMap::new();
+    for (id, node) in &graph.nodes {
+        if !rules.contains_key(&node.ruleset) {
+            undefined.entry(&node.ruleset).or_default().push(*id);
+        }
+    }
+    undefined
+        .into_iter()
+        .map(|(ruleset, nodes)| Diagnostic::UndefinedRuleset {
+            ruleset: ruleset.to_string(),
+            nodes,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diagnostics(code: &str) -> Vec<Diagnostic> {
+        compile(code).1
+    }
+
+    #[test]
+    fn rules_compile() {
+        let (rules, diagnostics) =
+            compile("a on = 1: on\nb[3]\n  state = 1: 2\n  else: 0\n\nc in > 0 & off = 0: on");
+        assert!(diagnostics.is_empty(), "{diagnostics:?}");
+        let mut names: Vec<&String> = rules.keys().collect();
+        names.sort();
+        assert_eq!(names, ["a", "b", "c"]);
+        assert_eq!(rules["b"].states, 3);
+        assert_eq!(rules["b"].cases.len(), 1);
+    }
+
+    #[test]
+    fn syntax_errors_point_at_their_line_and_column() {
+        match &diagnostics("a on = 1: on\nb\n  state = 1: 2\n  state = : 2")[..] {
+            [Diagnostic::Syntax {
+                line, column, rule, ..
+            }] => {
+                assert_eq!((*line, *column), (4, 11));
+                assert_eq!(rule.as_deref(), Some("b"));
+            }
+            other => panic!("unexpected diagnostics {other:?}"),
+        }
+    }
+
+    #[test]
+    fn cases_below_a_single_line_rule_are_reported_on_their_line() {
+        for code in ["a on = 1: on\n  c", "a on = 1: on\n\tstate = 1: 2"] {
+            assert_eq!(
+                diagnostics(code),
+                [Diagnostic::CaseAfterSingleLineRule {
+                    line: 2,
+                    rule: "a".to_string()
+                }],
+                "{code:?}"
+            );
+        }
+        assert_eq!(diagnostics("a on = 1: on\n\n  c")[0].line(), Some(3));
+    }
+
+    #[test]
+    fn duplicate_rules_are_reported() {
+        assert_eq!(
+            diagnostics("a on = 1: on\nb on = 2: on\na off = 1: on"),
+            [Diagnostic::DuplicateRule {
+                line: 3,
+                rule: "a".to_string(),
+                first_line: 1
+            }]
+        );
+    }
+
+    #[test]
+    fn wsum_takes_an_optional_class() {
+        let (rules, found) = compile("a wsum > 2: on\nb wsum(data) - wsum < 0: on");
+        assert!(found.is_empty(), "{found:?}");
+        let pattern = |rule: &str| format!("{:?}", rules[rule].cases[0].pattern);
+        assert!(pattern("a").contains("WeightedSum(None)"));
+        assert!(pattern("b").contains(r#"WeightedSum(Some("data"))"#));
+        assert!(pattern("b").contains("WeightedSum(None)"));
+        assert_eq!(diagnostics("a wsum(: on").len(), 1);
     }
 }
@@ -0,0 +1,273 @@
+use std::ops::RangeInclusive;
+
+/// The tempos that can be played, in beats per minute.
+pub const BPM_RANGE: RangeInclusive<f32> = 1.0..=999.0;
+
+/// How fast generations follow each other.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Tempo {
+    pub bpm: f32,
+    /// Generations per beat.
+    pub subdivision: u32,
+}
+
+impl Tempo {
+    /// `bpm` brought into `BPM_RANGE`, so a bad project file can't make
+    /// generations infinitely long or short.
+    pub fn clamped_bpm(&self) -> f32 {
+        // `max` before `min` turns NaN into the slowest tempo.
+        self.bpm.max(*BPM_RANGE.start()).min(*BPM_RANGE.end())
+    }
+
+    /// The length of one generation in seconds.
+    pub fn step_duration(&self) -> f64 {
+        60.0 / (self.clamped_bpm() as f64 * self.subdivision.max(1) as f64)
+    }
+}
+
+impl Default for Tempo {
+    fn default() -> Self {
+        Self {
+            bpm: 120.0,
+            subdivision: 1,
+        }
+    }
+}
+
+/// Turns elapsed time into generations. Time that isn't enough for a whole
+/// generation is carried over, so steps are never dropped or repeated however
+/// the frames are timed.
+#[derive(Clone, Debug, Default)]
+pub struct Clock {
+    elapsed: f64,
+}
+
+impl Clock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `seconds` of elapsed time and returns how many generations are
+    /// due.
+    pub fn advance(&mut self, tempo: &Tempo, seconds: f64) -> usize {
+        self.elapsed += seconds;
+        let step = tempo.step_duration();
+        let steps = (self.elapsed / step).floor();
+        self.elapsed -= steps * step;
+        steps as usize
+    }
+
+    pub fn reset(&mut self) {
+        self.elapsed = 0.0;
+    }
+}
+
+/// The number of MIDI clock messages per beat.
+pub const MIDI_CLOCKS_PER_BEAT: u32 = 24;
+
+/// Where the timing of generations comes from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum SyncMode {
+    /// Generations follow the tempo, timed by the computer's clock.
+    #[default]
+    Internal,
+    /// Generations follow the MIDI clock received from another device.
+    Follow,
+    /// Generations follow the tempo, and MIDI clock is sent for other devices
+    /// to follow.
+    Lead,
+}
+
+/// The MIDI messages that carry timing and transport.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClockMessage {
+    Clock,
+    Start,
+    Continue,
+    Stop,
+    /// The position to continue from, in sixteenth notes since the start.
+    SongPosition(u16),
+}
+
+impl ClockMessage {
+    /// Reads a timing or transport message, or returns `None` for any other
+    /// message.
+    pub fn decode(message: &[u8]) -> Option<Self> {
+        match midi_msg::MidiMsg::from_midi(message).ok()?.0 {
+            midi_msg::MidiMsg::SystemRealTime { msg } => match msg {
+                midi_msg::SystemRealTimeMsg::TimingClock => Some(ClockMessage::Clock),
+                midi_msg::SystemRealTimeMsg::Start => Some(ClockMessage::Start),
+                midi_msg::SystemRealTimeMsg::Continue => Some(ClockMessage::Continue),
+                midi_msg::SystemRealTimeMsg::Stop => Some(ClockMessage::Stop),
+                _ => None,
+            },
+            midi_msg::MidiMsg::SystemCommon {
+                msg: midi_msg::SystemCommonMsg::SongPosition(position),
+            } => Some(ClockMessage::SongPosition(position)),
+            _ => None,
+        }
+    }
+
+    pub fn to_midi(&self) -> Vec<u8> {
+        match self {
+            ClockMessage::Clock => midi_msg::MidiMsg::SystemRealTime {
+                msg: midi_msg::SystemRealTimeMsg::TimingClock,
+            },
+            ClockMessage::Start => midi_msg::MidiMsg::SystemRealTime {
+                msg: midi_msg::SystemRealTimeMsg::Start,
+            },
+            ClockMessage::Continue => midi_msg::MidiMsg::SystemRealTime {
+                msg: midi_msg::SystemRealTimeMsg::Continue,
+            },
+            ClockMessage::Stop => midi_msg::MidiMsg::SystemRealTime {
+                msg: midi_msg::SystemRealTimeMsg::Stop,
+            },
+            ClockMessage::SongPosition(position) => midi_msg::MidiMsg::SystemCommon {
+                msg: midi_msg::SystemCommonMsg::SongPosition(*position),
+            },
+        }
+        .to_midi()
+    }
+}
+
+/// Counts MIDI clocks since the start of the song and tells when generations
+/// are due, so that they fall on the same clocks as the beats they belong to.
+#[derive(Clone, Debug, Default)]
+pub struct MidiClock {
+    position: u64,
+}
+
+impl MidiClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts one clock and returns how many generations start on it.
+    pub fn tick(&mut self, tempo: &Tempo) -> usize {
+        let subdivision = tempo.subdivision.max(1) as u64;
+        let clocks = MIDI_CLOCKS_PER_BEAT as u64;
+        // The generations starting at or after `position`, but before the
+        // next clock.
+        let due = ((self.position + 1) * subdivision).div_ceil(clocks)
+            - (self.position * subdivision).div_ceil(clocks);
+        self.position += 1;
+        due as usize
+    }
+
+    /// Moves to a song position message's position.
+    pub fn set_song_position(&mut self, sixteenths: u16) {
+        self.position = sixteenths as u64 * MIDI_CLOCKS_PER_BEAT as u64 / 4;
+    }
+
+    pub fn reset(&mut self) {
+        self.position = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tempo(bpm: f32) -> Tempo {
+        Tempo {
+            bpm,
+            subdivision: 1,
+        }
+    }
+
+    #[test]
+    fn step_duration_follows_bpm() {
+        assert_eq!(tempo(120.0).step_duration(), 0.5);
+        let tempo = Tempo {
+            bpm: 60.0,
+            subdivision: 4,
+        };
+        assert_eq!(tempo.step_duration(), 0.25);
+    }
+
+    #[test]
+    fn bad_bpm_is_clamped() {
+        for bpm in [0.0, -120.0, f32::NAN, f32::NEG_INFINITY] {
+            assert_eq!(tempo(bpm).step_duration(), 60.0, "{bpm}");
+        }
+        assert_eq!(tempo(f32::INFINITY).clamped_bpm(), 999.0);
+        assert!(tempo(1e9).step_duration() > 0.0);
+    }
+
+    #[test]
+    fn clock_with_bad_bpm_still_steps() {
+        let mut clock = Clock::new();
+        assert_eq!(clock.advance(&tempo(0.0), 59.0), 0);
+        assert_eq!(clock.advance(&tempo(0.0), 1.0), 1);
+        let mut clock = Clock::new();
+        assert_eq!(clock.advance(&tempo(-1.0), 120.0), 2);
+    }
+
+    /// The clocks out of the next `count` that start generations at
+    /// `subdivision` generations per beat.
+    fn due_clocks(clock: &mut MidiClock, subdivision: u32, count: usize) -> Vec<usize> {
+        let tempo = Tempo {
+            bpm: 120.0,
+            subdivision,
+        };
+        let mut due = vec![];
+        for index in 0..count {
+            due.extend(std::iter::repeat_n(index, clock.tick(&tempo)));
+        }
+        due
+    }
+
+    #[test]
+    fn midi_clock_spreads_generations_over_the_beat() {
+        let due = |subdivision| due_clocks(&mut MidiClock::new(), subdivision, 48);
+        assert_eq!(due(1), [0, 24]);
+        assert_eq!(due(4), [0, 6, 12, 18, 24, 30, 36, 42]);
+        assert_eq!(due(3), [0, 8, 16, 24, 32, 40]);
+        // Uneven subdivisions start each generation on the last clock at or
+        // before its exact time, e.g. the second one, due at 4.8, on clock 4.
+        assert_eq!(due(5), [0, 4, 9, 14, 19, 24, 28, 33, 38, 43]);
+        // Faster than the clock, several generations start on one clock.
+        assert_eq!(due_clocks(&mut MidiClock::new(), 48, 2), [0, 0, 1, 1]);
+        // A subdivision of 0 counts as 1.
+        assert_eq!(due(0), [0, 24]);
+    }
+
+    #[test]
+    fn song_position_moves_the_clock() {
+        let mut clock = MidiClock::new();
+        due_clocks(&mut clock, 1, 7);
+        // Four sixteenths are one beat, so the next clock starts a beat.
+        clock.set_song_position(4);
+        assert_eq!(due_clocks(&mut clock, 1, 24), [0]);
+        // Two sixteenths are half a beat into it.
+        clock.set_song_position(2);
+        assert_eq!(due_clocks(&mut clock, 1, 24), [12]);
+        clock.set_song_position(2);
+        assert_eq!(due_clocks(&mut clock, 2, 12), [0]);
+        clock.reset();
+        assert_eq!(due_clocks(&mut clock, 1, 1), [0]);
+    }
+
+    #[test]
+    fn clock_messages_are_decoded() {
+        assert_eq!(ClockMessage::decode(&[0xf8]), Some(ClockMessage::Clock));
+        assert_eq!(ClockMessage::decode(&[0xfa]), Some(ClockMessage::Start));
+        assert_eq!(ClockMessage::decode(&[0xfb]), Some(ClockMessage::Continue));
+        assert_eq!(ClockMessage::decode(&[0xfc]), Some(ClockMessage::Stop));
+        // The position is 14 bits, least significant seven first.
+        assert_eq!(
+            ClockMessage::decode(&[0xf2, 0x10, 0x01]),
+            Some(ClockMessage::SongPosition(144))
+        );
+        assert_eq!(ClockMessage::decode(&[0x90, 60, 100]), None);
+        for message in [
+            ClockMessage::Clock,
+            ClockMessage::Start,
+            ClockMessage::Continue,
+            ClockMessage::Stop,
+            ClockMessage::SongPosition(1000),
+        ] {
+            assert_eq!(ClockMessage::decode(&message.to_midi()), Some(message));
+        }
+    }
+}
@@ -1,36 +1,103 @@
-use std::ops::{Index, IndexMut};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::{self, Display},
+    ops::{Index, IndexMut},
+};
 
-use crate::{app::App, note::Note, vec2::Vector2};
+use serde::{
+    de::{MapAccess, SeqAccess, Visitor},
+    Deserialize, Deserializer,
+};
+
+use crate::{midi::MidiSettings, note::Note, vec2::Vector2};
+
+/// Identifies a node for as long as it exists. Ids are never reused, so they
+/// stay valid when other nodes are removed.
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize,
+)]
+#[serde(transparent)]
+pub struct NodeId(pub usize);
+
+impl Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// The state of a cell. 0 is off; every other state counts as on.
+pub type State = u8;
+
+/// A connection into a node from `source`.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(from = "SavedEdge")]
+pub struct Edge {
+    pub source: NodeId,
+    /// How much the source counts toward `wsum` while it is on. Negative
+    /// weights inhibit.
+    pub weight: i32,
+    /// How many generations late the source's state arrives. At 0 the node
+    /// sees the source's state from the previous generation.
+    pub delay: usize,
+    /// The name rules count the edge under, like `clock` in `on(clock)`.
+    /// Empty for edges without a class.
+    pub class: String,
+}
+
+impl Edge {
+    /// An edge from `source` with weight 1, no delay and no class.
+    pub fn new(source: NodeId) -> Self {
+        Self {
+            source,
+            weight: 1,
+            delay: 0,
+            class: String::new(),
+        }
+    }
+
+    /// Whether the edge counts toward an expression limited to `class`, or
+    /// toward one over all edges if `class` is `None`.
+    pub fn is_in(&self, class: Option<&str>) -> bool {
+        class.is_none_or(|class| self.class == class)
+    }
+}
 
 #[derive(Clone, serde::Serialize, serde::Deserialize, Debug)]
 pub struct Node {
-    pub read: bool,
-    pub write: bool,
-    pub edges: Vec<usize>,
+    #[serde(deserialize_with = "deserialize_state")]
+    pub read: State,
+    #[serde(deserialize_with = "deserialize_state")]
+    pub write: State,
+    pub edges: Vec<Edge>,
     pub position: Vector2,
     pub note: Option<Note>,
+    #[serde(default)]
+    pub midi: MidiSettings,
     pub ruleset: String,
+    /// The update group, used by [`UpdateMode::Blocks`](crate::automaton::UpdateMode::Blocks).
+    #[serde(default)]
+    pub group: usize,
 }
 
-impl Index<usize> for Graph {
+impl Index<NodeId> for Graph {
     type Output = Node;
 
-    fn index(&self, index: usize) -> &Self::Output {
-        &self.nodes[index]
+    fn index(&self, index: NodeId) -> &Self::Output {
+        &self.nodes[&index]
     }
 }
 
-impl IndexMut<usize> for Graph {
-    fn index_mut(&mut self, index: usize) -> &mut Self::Output {
-        &mut self.nodes[index]
+impl IndexMut<NodeId> for Graph {
+    fn index_mut(&mut self, index: NodeId) -> &mut Self::Output {
+        self.nodes.get_mut(&index).expect("no node with this id")
     }
 }
 
 impl Node {
     pub fn new(
-        read: bool,
-        write: bool,
-        edges: Vec<usize>,
+        read: State,
+        write: State,
+        edges: Vec<Edge>,
         position: Vector2,
         ruleset: String,
     ) -> Self {
@@ -40,81 +107,473 @@ impl Node {
             edges,
             position,
             note: None,
+            midi: MidiSettings::default(),
             ruleset,
+            group: 0,
         }
     }
 }
 
-#[derive(Clone, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(from = "SavedGraph")]
 pub struct Graph {
-    pub nodes: Vec<Node>,
+    pub nodes: BTreeMap<NodeId, Node>,
+    next_id: usize,
+    /// The nodes each node has an edge into. Edges are stored on the node
+    /// they lead into, so this is kept alongside them to find a node's
+    /// outputs without going through every node.
+    #[serde(skip)]
+    outputs: BTreeMap<NodeId, BTreeSet<NodeId>>,
 }
 
 impl Graph {
     pub fn new() -> Self {
-        Self { nodes: Vec::new() }
+        Self::default()
     }
 
-    pub fn copy(&self, selection: &[usize]) -> Self {
-        let mut indexes = vec![None; self.nodes.len()];
+    /// Copies the selected nodes and the edges between them into a new graph.
+    pub fn copy(&self, selection: &[NodeId]) -> Self {
+        let mut ids = HashMap::new();
         let mut new_graph = Graph::new();
 
         for selected in selection {
-            let mut new_node = self.nodes[*selected].clone();
+            let mut new_node = self[*selected].clone();
             new_node.edges = vec![];
-            new_graph.add_node(new_node);
-            indexes[*selected] = Some(new_graph.nodes.len() - 1)
-        }
-        println!("{:?}", indexes);
-        for (i, &selected) in selection.iter().enumerate() {
-            for j in &self.nodes[selected].edges {
-                if let Some(new_index) = indexes[*j] {
-                    println!("{i}");
-                    new_graph.add_edge(i, new_index);
+            ids.insert(*selected, new_graph.add_node(new_node));
+        }
+        for selected in selection {
+            for edge in &self[*selected].edges {
+                if let Some(new_source) = ids.get(&edge.source) {
+                    new_graph.insert_edge(
+                        ids[selected],
+                        Edge {
+                            source: *new_source,
+                            ..edge.clone()
+                        },
+                    );
                 }
             }
         }
         new_graph
     }
 
-    pub fn add_node(&mut self, node: Node) {
-        self.nodes.push(node)
+    /// Adds every node of `other` to this graph, moved by `offset`, and
+    /// returns the ids they were given.
+    pub fn paste(&mut self, other: &Graph, offset: Vector2) -> Vec<NodeId> {
+        let ids: HashMap<NodeId, NodeId> = other
+            .nodes
+            .iter()
+            .map(|(id, node)| {
+                let mut new_node = node.clone();
+                new_node.edges = vec![];
+                new_node.position += offset;
+                (*id, self.add_node(new_node))
+            })
+            .collect();
+
+        for (id, node) in &other.nodes {
+            for edge in &node.edges {
+                self.insert_edge(
+                    ids[id],
+                    Edge {
+                        source: ids[&edge.source],
+                        ..edge.clone()
+                    },
+                );
+            }
+        }
+        other.nodes.keys().map(|id| ids[id]).collect()
+    }
+
+    pub fn add_node(&mut self, node: Node) -> NodeId {
+        let id = NodeId(self.next_id);
+        self.insert_node(id, node);
+        id
     }
-    pub fn remove_node(&mut self, idx: usize) {
-        self.nodes.swap_remove(idx);
-        let len = self.nodes.len();
-        for node in self.nodes.iter_mut() {
-            node.edges.retain(|a| *a != idx);
-            node.edges = node
-                .edges
-                .iter()
-                .map(|a| if *a == len { idx } else { *a })
-                .collect();
+
+    /// Puts a node back under an id it had before, e.g. when undoing its
+    /// removal.
+    pub fn insert_node(&mut self, id: NodeId, node: Node) {
+        self.next_id = self.next_id.max(id.0 + 1);
+        for edge in &node.edges {
+            self.outputs.entry(edge.source).or_default().insert(id);
         }
+        self.nodes.insert(id, node);
     }
 
-    pub fn remove_node_from_app(&mut self, idx: usize, app: &mut App) {
-        self.remove_node(idx);
+    /// Removes a node along with the edges into and out of it.
+    pub fn remove_node(&mut self, id: NodeId) -> Option<Node> {
+        let removed = self.nodes.remove(&id)?;
+        for edge in &removed.edges {
+            if let Some(outputs) = self.outputs.get_mut(&edge.source) {
+                outputs.remove(&id);
+            }
+        }
+        for target in self.outputs.remove(&id).unwrap_or_default() {
+            if let Some(node) = self.nodes.get_mut(&target) {
+                node.edges.retain(|edge| edge.source != id);
+            }
+        }
+        Some(removed)
+    }
 
-        let len = self.nodes.len();
-        app.selected.retain(|a| *a != idx);
-        app.selected = app
-            .selected
-            .iter()
-            .map(|a| if *a == len { idx } else { *a })
-            .collect();
+    /// The nodes `id` has an edge into.
+    pub fn outputs(&self, id: NodeId) -> impl Iterator<Item = NodeId> + '_ {
+        self.outputs.get(&id).into_iter().flatten().copied()
+    }
+
+    /// Adds an edge into `u` from `v` with weight 1.
+    pub fn add_edge(&mut self, u: NodeId, v: NodeId) -> bool {
+        self.insert_edge(u, Edge::new(v))
     }
 
-    pub fn add_edge(&mut self, u: usize, v: usize) -> bool {
-        if !self.nodes[u].edges.contains(&v) {
-            self.nodes[u].edges.push(v);
+    /// Adds `edge` into `u`, unless `u` already has an edge from the same
+    /// source.
+    pub fn insert_edge(&mut self, u: NodeId, edge: Edge) -> bool {
+        if self.edge(u, edge.source).is_none() {
+            self.outputs.entry(edge.source).or_default().insert(u);
+            self[u].edges.push(edge);
             true
         } else {
             false
         }
     }
 
-    pub fn remove_edge(&mut self, u: usize, v: usize) {
-        self.nodes[u].edges.retain(|a| *a != v);
+    /// The edge into `u` from `v`.
+    pub fn edge(&self, u: NodeId, v: NodeId) -> Option<&Edge> {
+        self[u].edges.iter().find(|edge| edge.source == v)
+    }
+
+    /// The edge into `u` from `v`, to change anything but its source.
+    pub fn edge_mut(&mut self, u: NodeId, v: NodeId) -> Option<&mut Edge> {
+        self[u].edges.iter_mut().find(|edge| edge.source == v)
+    }
+
+    pub fn remove_edge(&mut self, u: NodeId, v: NodeId) -> Option<Edge> {
+        let index = self[u].edges.iter().position(|edge| edge.source == v)?;
+        if let Some(outputs) = self.outputs.get_mut(&v) {
+            outputs.remove(&u);
+        }
+        Some(self[u].edges.remove(index))
+    }
+}
+
+/// Reads a state, accepting the booleans of files from before cells could
+/// have more than two states.
+fn deserialize_state<'de, D: Deserializer<'de>>(deserializer: D) -> Result<State, D::Error> {
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum SavedState {
+        Bool(bool),
+        State(State),
+    }
+
+    Ok(match SavedState::deserialize(deserializer)? {
+        SavedState::Bool(on) => on as State,
+        SavedState::State(state) => state,
+    })
+}
+
+/// An edge as saved, either as just its source, as in files from before
+/// edges had weights, or with all its fields.
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum SavedEdge {
+    Source(NodeId),
+    Edge {
+        source: NodeId,
+        #[serde(default = "default_weight")]
+        weight: i32,
+        #[serde(default)]
+        delay: usize,
+        #[serde(default)]
+        class: String,
+    },
+}
+
+fn default_weight() -> i32 {
+    1
+}
+
+impl From<SavedEdge> for Edge {
+    fn from(saved: SavedEdge) -> Self {
+        match saved {
+            SavedEdge::Source(source) => Edge::new(source),
+            SavedEdge::Edge {
+                source,
+                weight,
+                delay,
+                class,
+            } => Edge {
+                source,
+                weight,
+                delay,
+                class,
+            },
+        }
+    }
+}
+
+/// The on-disk form of a [`Graph`].
+#[derive(serde::Deserialize)]
+struct SavedGraph {
+    nodes: SavedNodes,
+    #[serde(default)]
+    next_id: usize,
+}
+
+impl From<SavedGraph> for Graph {
+    fn from(saved: SavedGraph) -> Self {
+        let nodes = saved.nodes.0;
+        let next_id = nodes
+            .keys()
+            .next_back()
+            .map_or(0, |id| id.0 + 1)
+            .max(saved.next_id);
+        let mut graph = Self {
+            nodes: BTreeMap::new(),
+            next_id,
+            outputs: BTreeMap::new(),
+        };
+        for (id, node) in nodes {
+            graph.insert_node(id, node);
+        }
+        graph
+    }
+}
+
+/// Nodes keyed by id. Files saved before nodes had ids store them as a list
+/// instead, where the position in the list was the id.
+struct SavedNodes(BTreeMap<NodeId, Node>);
+
+impl<'de> Deserialize<'de> for SavedNodes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct NodesVisitor;
+
+        impl<'de> Visitor<'de> for NodesVisitor {
+            type Value = SavedNodes;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a map or list of nodes")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                let mut nodes = BTreeMap::new();
+                while let Some((id, node)) = map.next_entry()? {
+                    nodes.insert(id, node);
+                }
+                Ok(SavedNodes(nodes))
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let mut nodes = BTreeMap::new();
+                while let Some(node) = seq.next_element()? {
+                    nodes.insert(NodeId(nodes.len()), node);
+                }
+                Ok(SavedNodes(nodes))
+            }
+        }
+
+        deserializer.deserialize_any(NodesVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node() -> Node {
+        Node::new(0, 0, vec![], Vector2::zero(), "xor".to_string())
+    }
+
+    /// A graph of `count` nodes.
+    fn graph(count: usize) -> Graph {
+        let mut graph = Graph::new();
+        for _ in 0..count {
+            graph.add_node(node());
+        }
+        graph
+    }
+
+    fn ids(graph: &Graph) -> Vec<usize> {
+        graph.nodes.keys().map(|id| id.0).collect()
+    }
+
+    #[test]
+    fn ids_survive_deletion() {
+        let mut graph = graph(3);
+        graph.add_edge(NodeId(2), NodeId(0));
+        graph.remove_node(NodeId(1));
+        assert_eq!(ids(&graph), [0, 2]);
+        assert!(graph.edge(NodeId(2), NodeId(0)).is_some());
+        assert_eq!(graph.add_node(node()), NodeId(3));
+    }
+
+    #[test]
+    fn ids_of_deleted_nodes_are_not_reused_after_loading() {
+        let mut graph = graph(3);
+        graph.remove_node(NodeId(2));
+        let text = serde_json::to_string(&graph).unwrap();
+        let mut loaded: Graph = serde_json::from_str(&text).unwrap();
+        assert_eq!(ids(&loaded), [0, 1]);
+        assert_eq!(loaded.add_node(node()), NodeId(3));
+    }
+
+    #[test]
+    fn old_list_files_load_with_ids_from_their_position() {
+        let text = r#"{"nodes": [
+            {"read": false, "write": true, "edges": [2], "position": {"x": 0, "y": 0}, "note": null, "ruleset": "xor"},
+            {"read": false, "write": false, "edges": [0], "position": {"x": 1, "y": 0}, "note": null, "ruleset": "xor"},
+            {"read": true, "write": false, "edges": [1, 0], "position": {"x": 2, "y": 0}, "note": null, "ruleset": "and"}
+        ]}"#;
+        let mut graph: Graph = serde_json::from_str(text).unwrap();
+        assert_eq!(ids(&graph), [0, 1, 2]);
+        assert_eq!(graph[NodeId(0)].write, 1);
+        assert_eq!(graph[NodeId(2)].read, 1);
+        assert_eq!(graph[NodeId(2)].ruleset, "and");
+        assert_eq!(
+            graph[NodeId(2)].edges,
+            [Edge::new(NodeId(1)), Edge::new(NodeId(0))]
+        );
+        assert_eq!(graph.add_node(node()), NodeId(3));
+    }
+
+    /// A graph of four nodes in a loop, with a shortcut from 0 to 2 and an
+    /// edge from node 1 into itself.
+    fn looped_graph() -> Graph {
+        let mut graph = graph(4);
+        for (u, v) in [(1, 0), (2, 1), (3, 2), (0, 3), (2, 0), (1, 1)] {
+            assert!(graph.add_edge(NodeId(u), NodeId(v)));
+        }
+        graph
+    }
+
+    fn outputs(graph: &Graph, id: usize) -> Vec<usize> {
+        graph.outputs(NodeId(id)).map(|id| id.0).collect()
+    }
+
+    /// Checks that the outputs `graph` keeps are exactly the ones its edges
+    /// give, and that every edge comes from a node of the graph.
+    fn assert_outputs_match_edges(graph: &Graph) {
+        let mut expected: BTreeMap<NodeId, BTreeSet<NodeId>> = BTreeMap::new();
+        for (id, node) in &graph.nodes {
+            for edge in &node.edges {
+                assert!(
+                    graph.nodes.contains_key(&edge.source),
+                    "edge into {id} from missing node {}",
+                    edge.source
+                );
+                expected.entry(edge.source).or_default().insert(*id);
+            }
+        }
+        let kept: BTreeMap<NodeId, BTreeSet<NodeId>> = graph
+            .outputs
+            .iter()
+            .filter(|(_, targets)| !targets.is_empty())
+            .map(|(id, targets)| (*id, targets.clone()))
+            .collect();
+        assert_eq!(kept, expected);
+    }
+
+    #[test]
+    fn outputs_follow_added_and_removed_edges() {
+        let mut graph = looped_graph();
+        assert_outputs_match_edges(&graph);
+        assert_eq!(outputs(&graph, 0), [1, 2]);
+        assert_eq!(outputs(&graph, 1), [1, 2]);
+
+        assert!(!graph.add_edge(NodeId(2), NodeId(0)));
+        assert!(graph.remove_edge(NodeId(2), NodeId(0)).is_some());
+        assert!(graph.remove_edge(NodeId(2), NodeId(0)).is_none());
+        assert!(graph.remove_edge(NodeId(1), NodeId(1)).is_some());
+        assert_outputs_match_edges(&graph);
+        assert_eq!(outputs(&graph, 0), [1]);
+        assert_eq!(outputs(&graph, 1), [2]);
+    }
+
+    #[test]
+    fn outputs_follow_removed_nodes() {
+        let mut graph = looped_graph();
+        graph.remove_node(NodeId(1));
+        assert_outputs_match_edges(&graph);
+        assert!(!graph.outputs.contains_key(&NodeId(1)));
+        assert_eq!(outputs(&graph, 0), [2]);
+        assert_eq!(graph[NodeId(2)].edges, [Edge::new(NodeId(0))]);
+
+        graph.remove_node(NodeId(0));
+        assert_outputs_match_edges(&graph);
+        assert!(graph[NodeId(2)].edges.is_empty());
+        assert_eq!(outputs(&graph, 3), [] as [usize; 0]);
+    }
+
+    #[test]
+    fn outputs_follow_copy_and_paste() {
+        let mut graph = looped_graph();
+        let copied = graph.copy(&[NodeId(0), NodeId(1), NodeId(2)]);
+        assert_outputs_match_edges(&copied);
+        // The edges to and from node 3 are left behind.
+        assert_eq!(outputs(&copied, 2), [] as [usize; 0]);
+
+        let pasted = graph.paste(&copied, Vector2::zero());
+        assert_outputs_match_edges(&graph);
+        assert_eq!(pasted, [NodeId(4), NodeId(5), NodeId(6)]);
+        assert_eq!(outputs(&graph, 4), [5, 6]);
+        assert_eq!(outputs(&graph, 0), [1, 2]);
+    }
+
+    #[test]
+    fn outputs_are_rebuilt_when_loading() {
+        let mut graph = looped_graph();
+        graph.remove_node(NodeId(3));
+        let text = serde_json::to_string(&graph).unwrap();
+        let loaded: Graph = serde_json::from_str(&text).unwrap();
+        assert_outputs_match_edges(&loaded);
+        for id in 0..3 {
+            assert_eq!(outputs(&loaded, id), outputs(&graph, id));
+        }
+    }
+
+    /// The edges of `graph` as `(target, source)` pairs, in no particular
+    /// order since undoing puts edges back at the end.
+    fn edges(graph: &Graph) -> BTreeSet<(usize, usize)> {
+        graph
+            .nodes
+            .iter()
+            .flat_map(|(id, node)| node.edges.iter().map(|edge| (id.0, edge.source.0)))
+            .collect()
+    }
+
+    #[test]
+    fn outputs_follow_undo_and_redo() {
+        use crate::history::{Edit, History};
+
+        let mut graph = looped_graph();
+        let before = edges(&graph);
+        let mut history = History::new();
+        let edits = [
+            Edit::AddEdge(NodeId(3), Edge::new(NodeId(0))),
+            Edit::RemoveEdge(NodeId(2), Edge::new(NodeId(1))),
+            Edit::remove_nodes(&graph, &[NodeId(0), NodeId(1)]),
+            Edit::AddNodes(vec![(NodeId(4), node())]),
+            Edit::AddEdge(NodeId(4), Edge::new(NodeId(2))),
+        ];
+        for edit in edits {
+            history.apply(edit, &mut graph);
+            assert_outputs_match_edges(&graph);
+        }
+        let after = edges(&graph);
+        assert_eq!(outputs(&graph, 2), [3, 4]);
+
+        while history.undo(&mut graph) {
+            assert_outputs_match_edges(&graph);
+        }
+        assert_eq!(edges(&graph), before);
+        assert_eq!(outputs(&graph, 0), [1, 2]);
+
+        while history.redo(&mut graph) {
+            assert_outputs_match_edges(&graph);
+        }
+        assert_eq!(edges(&graph), after);
+        assert_eq!(outputs(&graph, 2), [3, 4]);
     }
 }
@@ -0,0 +1,152 @@
+use std::fs;
+
+use cellgraph::{
+    cellang,
+    clock::BPM_RANGE,
+    graph::{Graph, State},
+    saved_state::SavedState,
+    wav,
+};
+
+const USAGE: &str = "usage: cellgraph run <project.json> [steps] [--json] [--seed <seed>]";
+const RENDER_USAGE: &str =
+    "usage: cellgraph render <project.json> <output.wav> [steps] [--seed <seed>]";
+
+/// Runs a saved project without opening a window and prints the state of
+/// every node after each generation.
+///
+/// The plain output has one line per generation: the generation number
+/// followed by the state of each node as a single digit (`a`-`z` for states
+/// from 10 on). With `--json` the whole trace is printed as an array of
+/// generations instead. `--seed` replaces the seed saved in the project.
+pub fn run(args: &[String]) -> Result<(), String> {
+    let mut json = false;
+    let mut seed = None;
+    let mut positional = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--seed" => {
+                let value = args.next().ok_or(USAGE)?;
+                seed = Some(
+                    value
+                        .parse::<u64>()
+                        .map_err(|error| format!("invalid seed {value:?}: {error}"))?,
+                );
+            }
+            _ => positional.push(arg),
+        }
+    }
+    let mut positional = positional.into_iter();
+
+    let path = positional.next().ok_or(USAGE)?;
+    let steps = match positional.next() {
+        Some(steps) => steps
+            .parse::<usize>()
+            .map_err(|error| format!("invalid step count {steps:?}: {error}"))?,
+        None => 100,
+    };
+
+    let state = load(path, seed)?;
+    let mut automaton = state.automaton;
+
+    let mut trace = vec![states(&automaton.graph)];
+    for _ in 0..steps {
+        automaton.step();
+        trace.push(states(&automaton.graph));
+    }
+
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string(&trace).map_err(|error| error.to_string())?
+        );
+    } else {
+        for (generation, states) in trace.iter().enumerate() {
+            let states: String = states
+                .iter()
+                .map(|&state| char::from_digit(state as u32, 36).unwrap_or('?'))
+                .collect();
+            println!("{generation} {states}");
+        }
+    }
+    Ok(())
+}
+
+/// Renders a saved project with the built-in synth and writes it to a WAV
+/// file, using the project's tempo, tuning and synth settings.
+pub fn render(args: &[String]) -> Result<(), String> {
+    let mut seed = None;
+    let mut positional = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--seed" => {
+                let value = args.next().ok_or(RENDER_USAGE)?;
+                seed = Some(
+                    value
+                        .parse::<u64>()
+                        .map_err(|error| format!("invalid seed {value:?}: {error}"))?,
+                );
+            }
+            _ => positional.push(arg),
+        }
+    }
+    let mut positional = positional.into_iter();
+
+    let path = positional.next().ok_or(RENDER_USAGE)?;
+    let output = positional.next().ok_or(RENDER_USAGE)?;
+    let steps = match positional.next() {
+        Some(steps) => steps
+            .parse::<usize>()
+            .map_err(|error| format!("invalid step count {steps:?}: {error}"))?,
+        None => 64,
+    };
+
+    let state = load(path, seed)?;
+    let bpm = state.tempo.bpm;
+    if !BPM_RANGE.contains(&bpm) {
+        eprintln!(
+            "{path}: the tempo of {bpm} bpm is outside {} to {} bpm, rendering at {} bpm",
+            BPM_RANGE.start(),
+            BPM_RANGE.end(),
+            state.tempo.clamped_bpm()
+        );
+    }
+    let data = wav::export(
+        &state.automaton,
+        steps,
+        &state.tempo,
+        &state.tuning,
+        &state.synth,
+    );
+    fs::write(output, data).map_err(|error| format!("unable to write {output}: {error}"))
+}
+
+/// Reads the project at `path`, compiles its rules and reports problems with
+/// them. `seed` replaces the seed saved in the project.
+fn load(path: &str, seed: Option<u64>) -> Result<SavedState, String> {
+    let serialized =
+        fs::read_to_string(path).map_err(|error| format!("unable to read {path}: {error}"))?;
+    let mut state = serde_json::from_str::<SavedState>(&serialized)
+        .map_err(|error| format!("unable to load {path}: {error}"))?;
+
+    let automaton = &mut state.automaton;
+    let (rules, diagnostics) = cellang::compile(&state.code);
+    automaton.rules = rules;
+    if let Some(seed) = seed {
+        automaton.reseed(seed);
+    }
+    for diagnostic in diagnostics.iter().chain(&cellang::check_references(
+        &automaton.rules,
+        &automaton.graph,
+    )) {
+        eprintln!("{path}: {diagnostic}");
+    }
+    Ok(state)
+}
+
+fn states(graph: &Graph) -> Vec<State> {
+    graph.nodes.values().map(|node| node.write).collect()
+}
@@ -0,0 +1,426 @@
+use crate::{
+    graph::{Edge, Graph, Node, NodeId, State},
+    midi::MidiSettings,
+    note::Note,
+    vec2::Vector2,
+};
+
+/// An edit of a graph that can be undone and redone.
+///
+/// Edits only touch what they change, so undoing one doesn't rewind the
+/// simulation.
+#[derive(Debug, Clone)]
+pub enum Edit {
+    AddNodes(Vec<(NodeId, Node)>),
+    /// The removed nodes, and the edges from them into nodes that stay, with
+    /// their targets.
+    RemoveNodes {
+        nodes: Vec<(NodeId, Node)>,
+        edges: Vec<(NodeId, Edge)>,
+    },
+    MoveNodes {
+        nodes: Vec<NodeId>,
+        offset: Vector2,
+    },
+    /// An edge into the node.
+    AddEdge(NodeId, Edge),
+    RemoveEdge(NodeId, Edge),
+    /// The edges as `(target, source)` pairs with the weight they had before,
+    /// and the weight they were given.
+    SetWeight {
+        edges: Vec<(NodeId, NodeId, i32)>,
+        weight: i32,
+    },
+    /// The edges as `(target, source)` pairs with the delay they had before,
+    /// and the delay they were given.
+    SetDelay {
+        edges: Vec<(NodeId, NodeId, usize)>,
+        delay: usize,
+    },
+    /// The edges as `(target, source)` pairs with the class they had before,
+    /// and the class they were given.
+    SetClass {
+        edges: Vec<(NodeId, NodeId, String)>,
+        class: String,
+    },
+    /// The nodes with the state they had before, and the state they were given.
+    SetState {
+        nodes: Vec<(NodeId, State)>,
+        state: State,
+    },
+    /// The nodes with the ruleset they had before, and the ruleset they were
+    /// given.
+    SetRuleset {
+        nodes: Vec<(NodeId, String)>,
+        ruleset: String,
+    },
+    /// The nodes with their note before and after.
+    SetNotes(Vec<(NodeId, Option<Note>, Option<Note>)>),
+    /// The nodes with the update group they had before, and the group they
+    /// were put in.
+    SetGroup {
+        nodes: Vec<(NodeId, usize)>,
+        group: usize,
+    },
+    /// The nodes with their MIDI settings before and after.
+    SetMidi(Vec<(NodeId, MidiSettings, MidiSettings)>),
+    /// Several edits that are undone and redone together.
+    Group(Vec<Edit>),
+}
+
+impl Edit {
+    /// The edit that removes `ids` from `graph`.
+    pub fn remove_nodes(graph: &Graph, ids: &[NodeId]) -> Self {
+        let nodes = ids
+            .iter()
+            .filter_map(|id| Some((*id, graph.nodes.get(id)?.clone())))
+            .collect();
+        let edges = graph
+            .nodes
+            .iter()
+            .filter(|(id, _)| !ids.contains(id))
+            .flat_map(|(id, node)| {
+                node.edges
+                    .iter()
+                    .filter(|edge| ids.contains(&edge.source))
+                    .map(|edge| (*id, edge.clone()))
+            })
+            .collect();
+        Edit::RemoveNodes { nodes, edges }
+    }
+
+    /// The edit that gives the edges into each target from each source in
+    /// `edges` the weight `weight`.
+    pub fn set_weight(graph: &Graph, edges: &[(NodeId, NodeId)], weight: i32) -> Self {
+        Edit::SetWeight {
+            edges: edges
+                .iter()
+                .filter_map(|(u, v)| Some((*u, *v, graph.edge(*u, *v)?.weight)))
+                .collect(),
+            weight,
+        }
+    }
+
+    /// The edit that gives the edges into each target from each source in
+    /// `edges` the delay `delay`.
+    pub fn set_delay(graph: &Graph, edges: &[(NodeId, NodeId)], delay: usize) -> Self {
+        Edit::SetDelay {
+            edges: edges
+                .iter()
+                .filter_map(|(u, v)| Some((*u, *v, graph.edge(*u, *v)?.delay)))
+                .collect(),
+            delay,
+        }
+    }
+
+    /// The edit that gives the edges into each target from each source in
+    /// `edges` the class `class`.
+    pub fn set_class(graph: &Graph, edges: &[(NodeId, NodeId)], class: &str) -> Self {
+        Edit::SetClass {
+            edges: edges
+                .iter()
+                .filter_map(|(u, v)| Some((*u, *v, graph.edge(*u, *v)?.class.clone())))
+                .collect(),
+            class: class.to_string(),
+        }
+    }
+
+    /// The edit that sets the state of `ids` in `graph`.
+    pub fn set_state(graph: &Graph, ids: &[NodeId], state: State) -> Self {
+        Edit::SetState {
+            nodes: ids.iter().map(|id| (*id, graph[*id].write)).collect(),
+            state,
+        }
+    }
+
+    /// The edit that gives `ids` in `graph` the ruleset `ruleset`.
+    pub fn set_ruleset(graph: &Graph, ids: &[NodeId], ruleset: &str) -> Self {
+        Edit::SetRuleset {
+            nodes: ids
+                .iter()
+                .map(|id| (*id, graph[*id].ruleset.clone()))
+                .collect(),
+            ruleset: ruleset.to_string(),
+        }
+    }
+
+    /// The edit that replaces the note of each of `ids` in `graph` with
+    /// `change(note)`.
+    pub fn set_notes(
+        graph: &Graph,
+        ids: &[NodeId],
+        change: impl Fn(Option<Note>) -> Option<Note>,
+    ) -> Self {
+        Edit::SetNotes(
+            ids.iter()
+                .map(|id| {
+                    let note = graph[*id].note.clone();
+                    (*id, note.clone(), change(note))
+                })
+                .collect(),
+        )
+    }
+
+    /// The edit that puts `ids` in `graph` in update group `group`.
+    pub fn set_group(graph: &Graph, ids: &[NodeId], group: usize) -> Self {
+        Edit::SetGroup {
+            nodes: ids.iter().map(|id| (*id, graph[*id].group)).collect(),
+            group,
+        }
+    }
+
+    /// The edit that replaces the MIDI settings of each of `ids` in `graph`
+    /// with `change(settings)`.
+    pub fn set_midi(
+        graph: &Graph,
+        ids: &[NodeId],
+        change: impl Fn(MidiSettings) -> MidiSettings,
+    ) -> Self {
+        Edit::SetMidi(
+            ids.iter()
+                .map(|id| {
+                    let midi = graph[*id].midi.clone();
+                    (*id, midi.clone(), change(midi))
+                })
+                .collect(),
+        )
+    }
+
+    fn apply(&self, graph: &mut Graph) {
+        match self {
+            Edit::AddNodes(nodes) => {
+                for (id, node) in nodes {
+                    graph.insert_node(*id, node.clone());
+                }
+            }
+            Edit::RemoveNodes { nodes, .. } => {
+                for (id, _) in nodes {
+                    graph.remove_node(*id);
+                }
+            }
+            Edit::MoveNodes { nodes, offset } => {
+                for id in nodes {
+                    if let Some(node) = graph.nodes.get_mut(id) {
+                        node.position += *offset;
+                    }
+                }
+            }
+            Edit::AddEdge(u, edge) => {
+                graph.insert_edge(*u, edge.clone());
+            }
+            Edit::RemoveEdge(u, edge) => {
+                graph.remove_edge(*u, edge.source);
+            }
+            Edit::SetWeight { edges, weight } => {
+                for (u, v, _) in edges {
+                    if let Some(edge) = graph.edge_mut(*u, *v) {
+                        edge.weight = *weight;
+                    }
+                }
+            }
+            Edit::SetDelay { edges, delay } => {
+                for (u, v, _) in edges {
+                    if let Some(edge) = graph.edge_mut(*u, *v) {
+                        edge.delay = *delay;
+                    }
+                }
+            }
+            Edit::SetClass { edges, class } => {
+                for (u, v, _) in edges {
+                    if let Some(edge) = graph.edge_mut(*u, *v) {
+                        edge.class = class.clone();
+                    }
+                }
+            }
+            Edit::SetState { nodes, state } => {
+                for (id, _) in nodes {
+                    if let Some(node) = graph.nodes.get_mut(id) {
+                        node.write = *state;
+                    }
+                }
+            }
+            Edit::SetRuleset { nodes, ruleset } => {
+                for (id, _) in nodes {
+                    if let Some(node) = graph.nodes.get_mut(id) {
+                        node.ruleset = ruleset.clone();
+                    }
+                }
+            }
+            Edit::SetNotes(nodes) => {
+                for (id, _, note) in nodes {
+                    if let Some(node) = graph.nodes.get_mut(id) {
+                        node.note = note.clone();
+                    }
+                }
+            }
+            Edit::SetGroup { nodes, group } => {
+                for (id, _) in nodes {
+                    if let Some(node) = graph.nodes.get_mut(id) {
+                        node.group = *group;
+                    }
+                }
+            }
+            Edit::SetMidi(nodes) => {
+                for (id, _, midi) in nodes {
+                    if let Some(node) = graph.nodes.get_mut(id) {
+                        node.midi = midi.clone();
+                    }
+                }
+            }
+            Edit::Group(edits) => {
+                for edit in edits {
+                    edit.apply(graph);
+                }
+            }
+        }
+    }
+
+    fn revert(&self, graph: &mut Graph) {
+        match self {
+            Edit::AddNodes(nodes) => {
+                for (id, _) in nodes {
+                    graph.remove_node(*id);
+                }
+            }
+            Edit::RemoveNodes { nodes, edges } => {
+                for (id, node) in nodes {
+                    graph.insert_node(*id, node.clone());
+                }
+                for (target, edge) in edges {
+                    if graph.nodes.contains_key(target) {
+                        graph.insert_edge(*target, edge.clone());
+                    }
+                }
+            }
+            Edit::MoveNodes { nodes, offset } => {
+                for id in nodes {
+                    if let Some(node) = graph.nodes.get_mut(id) {
+                        node.position -= *offset;
+                    }
+                }
+            }
+            Edit::AddEdge(u, edge) => {
+                graph.remove_edge(*u, edge.source);
+            }
+            Edit::RemoveEdge(u, edge) => {
+                graph.insert_edge(*u, edge.clone());
+            }
+            Edit::SetWeight { edges, .. } => {
+                for (u, v, weight) in edges {
+                    if let Some(edge) = graph.edge_mut(*u, *v) {
+                        edge.weight = *weight;
+                    }
+                }
+            }
+            Edit::SetDelay { edges, .. } => {
+                for (u, v, delay) in edges {
+                    if let Some(edge) = graph.edge_mut(*u, *v) {
+                        edge.delay = *delay;
+                    }
+                }
+            }
+            Edit::SetClass { edges, .. } => {
+                for (u, v, class) in edges {
+                    if let Some(edge) = graph.edge_mut(*u, *v) {
+                        edge.class = class.clone();
+                    }
+                }
+            }
+            Edit::SetState { nodes, .. } => {
+                for (id, state) in nodes {
+                    if let Some(node) = graph.nodes.get_mut(id) {
+                        node.write = *state;
+                    }
+                }
+            }
+            Edit::SetRuleset { nodes, .. } => {
+                for (id, ruleset) in nodes {
+                    if let Some(node) = graph.nodes.get_mut(id) {
+                        node.ruleset = ruleset.clone();
+                    }
+                }
+            }
+            Edit::SetNotes(nodes) => {
+                for (id, note, _) in nodes {
+                    if let Some(node) = graph.nodes.get_mut(id) {
+                        node.note = note.clone();
+                    }
+                }
+            }
+            Edit::SetGroup { nodes, .. } => {
+                for (id, group) in nodes {
+                    if let Some(node) = graph.nodes.get_mut(id) {
+                        node.group = *group;
+                    }
+                }
+            }
+            Edit::SetMidi(nodes) => {
+                for (id, midi, _) in nodes {
+                    if let Some(node) = graph.nodes.get_mut(id) {
+                        node.midi = midi.clone();
+                    }
+                }
+            }
+            Edit::Group(edits) => {
+                for edit in edits.iter().rev() {
+                    edit.revert(graph);
+                }
+            }
+        }
+    }
+}
+
+/// The edits that can be undone, and the undone edits that can be redone.
+#[derive(Debug, Clone, Default)]
+pub struct History {
+    undo: Vec<Edit>,
+    redo: Vec<Edit>,
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes `edit` to `graph` and records it.
+    pub fn apply(&mut self, edit: Edit, graph: &mut Graph) {
+        edit.apply(graph);
+        self.push(edit);
+    }
+
+    /// Records an edit that has already been made.
+    pub fn push(&mut self, edit: Edit) {
+        self.undo.push(edit);
+        self.redo.clear();
+    }
+
+    /// Reverts the last edit. Returns false if there was nothing to undo.
+    pub fn undo(&mut self, graph: &mut Graph) -> bool {
+        match self.undo.pop() {
+            Some(edit) => {
+                edit.revert(graph);
+                self.redo.push(edit);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Makes the last undone edit again. Returns false if there was nothing to
+    /// redo.
+    pub fn redo(&mut self, graph: &mut Graph) -> bool {
+        match self.redo.pop() {
+            Some(edit) => {
+                edit.apply(graph);
+                self.undo.push(edit);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+    }
+}
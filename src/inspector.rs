@@ -0,0 +1,483 @@
+use std::collections::BTreeMap;
+
+use cellgraph::{
+    automaton::Automaton,
+    graph::{Edge, NodeId, State},
+    history::{Edit, History},
+    midi::{MidiSettings, Velocity, VelocityExpression},
+    note::{Accidental, Note, NoteLetter},
+    vec2::Vector2,
+};
+use egui_macroquad::egui::{ComboBox, DragValue, Grid, TextEdit, Ui};
+
+/// Shown in place of a value that differs between the selected nodes.
+const MIXED: &str = "mixed";
+
+/// The panel that shows and edits the properties of the selected nodes.
+pub struct Inspector {
+    /// How far the position fields have moved the selection since they were
+    /// grabbed.
+    moved: Vector2,
+    /// The velocity expression being typed, applied when the field loses
+    /// focus.
+    velocity: String,
+    editing_velocity: bool,
+    /// The class being typed for the inputs from a source, applied when the
+    /// field loses focus.
+    class: String,
+    editing_class: Option<NodeId>,
+}
+
+impl Inspector {
+    pub fn new() -> Self {
+        Self {
+            moved: Vector2::zero(),
+            velocity: String::new(),
+            editing_velocity: false,
+            class: String::new(),
+            editing_class: None,
+        }
+    }
+
+    pub fn show(
+        &mut self,
+        ui: &mut Ui,
+        automaton: &mut Automaton,
+        selected: &[NodeId],
+        history: &mut History,
+    ) {
+        match selected {
+            [] => {
+                ui.label("no node selected");
+                return;
+            }
+            [id] => ui.heading(format!("node {id}")),
+            _ => ui.heading(format!("{} nodes", selected.len())),
+        };
+
+        let graph = &automaton.graph;
+        let nodes: Vec<_> = selected.iter().map(|id| &graph[*id]).collect();
+        let mut edits = vec![];
+        let mut moved = Vector2::zero();
+        let mut released = false;
+
+        Grid::new("inspector grid").num_columns(2).show(ui, |ui| {
+            ui.label("ruleset");
+            let ruleset = common(nodes.iter().map(|node| &node.ruleset));
+            let mut names: Vec<&String> = automaton.rules.keys().collect();
+            names.sort();
+            ComboBox::from_id_source("inspector ruleset")
+                .selected_text(ruleset.map_or(MIXED, String::as_str))
+                .show_ui(ui, |ui| {
+                    for name in names {
+                        if ui.selectable_label(ruleset == Some(name), name).clicked() {
+                            edits.push(Edit::set_ruleset(graph, selected, name));
+                        }
+                    }
+                });
+            ui.end_row();
+
+            ui.label("state");
+            let state = common(nodes.iter().map(|node| node.write));
+            let mut new_state: State = state.unwrap_or(0);
+            if ui
+                .add(mixed(DragValue::new(&mut new_state), state.is_none()))
+                .changed()
+            {
+                edits.push(Edit::set_state(graph, selected, new_state));
+            }
+            ui.end_row();
+
+            ui.label("update group");
+            let group = common(nodes.iter().map(|node| node.group));
+            let mut new_group = group.unwrap_or(0);
+            if ui
+                .add(mixed(DragValue::new(&mut new_group), group.is_none()))
+                .changed()
+            {
+                edits.push(Edit::set_group(graph, selected, new_group));
+            }
+            ui.end_row();
+
+            ui.label("note");
+            let has_note = common(nodes.iter().map(|node| node.note.is_some()));
+            let mut new_has_note = has_note.unwrap_or(false);
+            let label = if has_note.is_some() { "" } else { MIXED };
+            if ui.checkbox(&mut new_has_note, label).changed() {
+                edits.push(Edit::set_notes(graph, selected, |note| {
+                    if new_has_note {
+                        note.or(Some(Note::new(NoteLetter::C, Accidental::Neutral, 4)))
+                    } else {
+                        None
+                    }
+                }));
+            }
+            ui.end_row();
+
+            let notes: Vec<&Note> = nodes.iter().filter_map(|node| node.note.as_ref()).collect();
+            if !notes.is_empty() {
+                ui.label("");
+                ui.horizontal(|ui| {
+                    let letter = common(notes.iter().map(|note| note.letter));
+                    ComboBox::from_id_source("inspector letter")
+                        .selected_text(letter.map_or(MIXED.to_string(), |l| format!("{l:?}")))
+                        .width(40.0)
+                        .show_ui(ui, |ui| {
+                            for option in NoteLetter::ALL {
+                                let label = format!("{option:?}");
+                                if ui.selectable_label(letter == Some(option), label).clicked() {
+                                    edits.push(Edit::set_notes(graph, selected, |note| {
+                                        note.map(|note| Note {
+                                            letter: option,
+                                            ..note
+                                        })
+                                    }));
+                                }
+                            }
+                        });
+
+                    let accidental = common(notes.iter().map(|note| note.accidental));
+                    ComboBox::from_id_source("inspector accidental")
+                        .selected_text(accidental.map_or(MIXED.to_string(), |a| format!("{a:?}")))
+                        .width(70.0)
+                        .show_ui(ui, |ui| {
+                            for option in Accidental::ALL {
+                                let label = format!("{option:?}");
+                                if ui
+                                    .selectable_label(accidental == Some(option), label)
+                                    .clicked()
+                                {
+                                    edits.push(Edit::set_notes(graph, selected, |note| {
+                                        note.map(|note| Note {
+                                            accidental: option,
+                                            ..note
+                                        })
+                                    }));
+                                }
+                            }
+                        });
+
+                    let octave = common(notes.iter().map(|note| note.octave));
+                    let mut new_octave = octave.unwrap_or(4);
+                    if ui
+                        .add(mixed(
+                            DragValue::new(&mut new_octave).clamp_range(-1..=9),
+                            octave.is_none(),
+                        ))
+                        .changed()
+                    {
+                        edits.push(Edit::set_notes(graph, selected, |note| {
+                            note.map(|note| Note {
+                                octave: new_octave,
+                                ..note
+                            })
+                        }));
+                    }
+                });
+                ui.end_row();
+            }
+
+            ui.label("channel");
+            let channel = common(nodes.iter().map(|node| node.midi.channel));
+            let mut new_channel = channel.unwrap_or(0) + 1;
+            if ui
+                .add(mixed(
+                    DragValue::new(&mut new_channel).clamp_range(1..=16),
+                    channel.is_none(),
+                ))
+                .changed()
+            {
+                edits.push(Edit::set_midi(graph, selected, |midi| MidiSettings {
+                    channel: new_channel - 1,
+                    ..midi
+                }));
+            }
+            ui.end_row();
+
+            ui.label("velocity");
+            ui.horizontal(|ui| {
+                let velocity = common(nodes.iter().map(|node| &node.midi.velocity));
+                let is_expression = common(
+                    nodes
+                        .iter()
+                        .map(|node| matches!(node.midi.velocity, Velocity::Expression(_))),
+                );
+                let kind = match is_expression {
+                    Some(false) => "fixed",
+                    Some(true) => "expression",
+                    None => MIXED,
+                };
+                ComboBox::from_id_source("inspector velocity")
+                    .selected_text(kind)
+                    .width(80.0)
+                    .show_ui(ui, |ui| {
+                        if ui
+                            .selectable_label(is_expression == Some(false), "fixed")
+                            .clicked()
+                        {
+                            edits.push(Edit::set_midi(graph, selected, |midi| MidiSettings {
+                                velocity: Velocity::Fixed(60),
+                                ..midi
+                            }));
+                        }
+                        if ui
+                            .selectable_label(is_expression == Some(true), "expression")
+                            .clicked()
+                        {
+                            edits.push(Edit::set_midi(graph, selected, |midi| MidiSettings {
+                                velocity: Velocity::Expression(VelocityExpression::new("on * 20")),
+                                ..midi
+                            }));
+                        }
+                    });
+                match is_expression {
+                    Some(false) => {
+                        let fixed = match velocity {
+                            Some(Velocity::Fixed(fixed)) => Some(*fixed),
+                            _ => None,
+                        };
+                        let mut new_fixed = fixed.unwrap_or(60);
+                        if ui
+                            .add(mixed(
+                                DragValue::new(&mut new_fixed).clamp_range(1..=127),
+                                fixed.is_none(),
+                            ))
+                            .changed()
+                        {
+                            edits.push(Edit::set_midi(graph, selected, |midi| MidiSettings {
+                                velocity: Velocity::Fixed(new_fixed),
+                                ..midi
+                            }));
+                        }
+                    }
+                    Some(true) => {
+                        if !self.editing_velocity {
+                            self.velocity = match velocity {
+                                Some(Velocity::Expression(expression)) => {
+                                    expression.text().to_string()
+                                }
+                                _ => String::new(),
+                            };
+                        }
+                        let response = ui.add(
+                            TextEdit::singleline(&mut self.velocity)
+                                .hint_text(MIXED)
+                                .desired_width(80.0),
+                        );
+                        self.editing_velocity = response.has_focus();
+                        if response.lost_focus() && !self.velocity.is_empty() {
+                            let expression = VelocityExpression::new(&self.velocity);
+                            edits.push(Edit::set_midi(graph, selected, |midi| MidiSettings {
+                                velocity: Velocity::Expression(expression.clone()),
+                                ..midi
+                            }));
+                        }
+                    }
+                    None => (),
+                }
+            });
+            ui.end_row();
+
+            ui.label("duration");
+            ui.horizontal(|ui| {
+                let duration = common(nodes.iter().map(|node| node.midi.duration));
+                let mut fixed = duration.is_some_and(|duration| duration.is_some());
+                let label = if duration.is_some() { "fixed" } else { MIXED };
+                if ui.checkbox(&mut fixed, label).changed() {
+                    edits.push(Edit::set_midi(graph, selected, |midi| MidiSettings {
+                        duration: fixed.then_some(1),
+                        ..midi
+                    }));
+                }
+                if let Some(Some(steps)) = duration {
+                    let mut new_steps = steps;
+                    if ui
+                        .add(
+                            DragValue::new(&mut new_steps)
+                                .clamp_range(1..=1024)
+                                .suffix(" steps"),
+                        )
+                        .changed()
+                    {
+                        edits.push(Edit::set_midi(graph, selected, |midi| MidiSettings {
+                            duration: Some(new_steps),
+                            ..midi
+                        }));
+                    }
+                }
+            });
+            ui.end_row();
+
+            ui.label("midi input");
+            let input = common(nodes.iter().map(|node| node.midi.input));
+            let mut new_input = input.unwrap_or(false);
+            let label = if input.is_some() { "any key" } else { MIXED };
+            if ui.checkbox(&mut new_input, label).changed() {
+                edits.push(Edit::set_midi(graph, selected, |midi| MidiSettings {
+                    input: new_input,
+                    ..midi
+                }));
+            }
+            ui.end_row();
+
+            ui.label("position");
+            ui.horizontal(|ui| {
+                let x = common(nodes.iter().map(|node| node.position.x));
+                let mut new_x = x.unwrap_or(0.0);
+                let response = ui.add(mixed(DragValue::new(&mut new_x).prefix("x "), x.is_none()));
+                moved.x = new_x - x.unwrap_or(0.0);
+                released |= response.drag_released() || response.lost_focus();
+
+                let y = common(nodes.iter().map(|node| node.position.y));
+                let mut new_y = y.unwrap_or(0.0);
+                let response = ui.add(mixed(DragValue::new(&mut new_y).prefix("y "), y.is_none()));
+                moved.y = new_y - y.unwrap_or(0.0);
+                released |= response.drag_released() || response.lost_focus();
+            });
+            ui.end_row();
+        });
+
+        ui.separator();
+        ui.label("inputs");
+        // Each source with its edges into the selected nodes.
+        let mut sources: BTreeMap<NodeId, Vec<Edge>> = BTreeMap::new();
+        for node in &nodes {
+            for edge in &node.edges {
+                sources.entry(edge.source).or_default().push(edge.clone());
+            }
+        }
+        Grid::new("inspector inputs").num_columns(5).show(ui, |ui| {
+            if !sources.is_empty() {
+                ui.label("");
+                ui.label("weight");
+                ui.label("delay");
+                ui.label("class");
+                ui.end_row();
+            }
+            for (source, edges) in &sources {
+                if edges.len() == selected.len() {
+                    ui.label(format!("from {source}"));
+                } else {
+                    ui.label(format!(
+                        "from {source} ({} of {})",
+                        edges.len(),
+                        selected.len()
+                    ));
+                }
+                let targets: Vec<(NodeId, NodeId)> = selected
+                    .iter()
+                    .filter(|id| graph.edge(**id, *source).is_some())
+                    .map(|id| (*id, *source))
+                    .collect();
+                let weight = common(edges.iter().map(|edge| edge.weight));
+                let mut new_weight = weight.unwrap_or(1);
+                if ui
+                    .add(mixed(DragValue::new(&mut new_weight), weight.is_none()))
+                    .changed()
+                {
+                    edits.push(Edit::set_weight(graph, &targets, new_weight));
+                }
+                let delay = common(edges.iter().map(|edge| edge.delay));
+                let mut new_delay = delay.unwrap_or(0);
+                if ui
+                    .add(mixed(DragValue::new(&mut new_delay), delay.is_none()))
+                    .changed()
+                {
+                    edits.push(Edit::set_delay(graph, &targets, new_delay));
+                }
+                let class = common(edges.iter().map(|edge| &edge.class));
+                let editing = self.editing_class == Some(*source);
+                let mut new_class = match class {
+                    _ if editing => self.class.clone(),
+                    Some(class) => class.clone(),
+                    None => String::new(),
+                };
+                let response = ui.add(
+                    TextEdit::singleline(&mut new_class)
+                        .hint_text(if class.is_some() { "" } else { MIXED })
+                        .desired_width(60.0),
+                );
+                if response.has_focus() {
+                    self.editing_class = Some(*source);
+                    self.class = new_class.clone();
+                } else if editing {
+                    self.editing_class = None;
+                }
+                // Rules can only name classes made of lowercase letters, and
+                // leaving a mixed field empty keeps the classes as they are.
+                let valid = new_class.chars().all(|c| c.is_ascii_lowercase())
+                    && (class.is_some() || !new_class.is_empty());
+                if response.lost_focus() && valid && class != Some(&new_class) {
+                    edits.push(Edit::set_class(graph, &targets, &new_class));
+                }
+                if ui.small_button("x").clicked() {
+                    edits.push(Edit::Group(
+                        targets
+                            .iter()
+                            .filter_map(|(u, v)| {
+                                Some(Edit::RemoveEdge(*u, graph.edge(*u, *v)?.clone()))
+                            })
+                            .collect(),
+                    ));
+                }
+                ui.end_row();
+            }
+        });
+        ComboBox::from_id_source("inspector add input")
+            .selected_text("add input")
+            .show_ui(ui, |ui| {
+                for source in graph.nodes.keys() {
+                    if sources
+                        .get(source)
+                        .is_some_and(|edges| edges.len() == selected.len())
+                    {
+                        continue;
+                    }
+                    if ui.selectable_label(false, source.to_string()).clicked() {
+                        edits.push(Edit::Group(
+                            selected
+                                .iter()
+                                .filter(|id| graph.edge(**id, *source).is_none())
+                                .map(|id| Edit::AddEdge(*id, Edge::new(*source)))
+                                .collect(),
+                        ));
+                    }
+                }
+            });
+
+        for edit in edits {
+            history.apply(edit, &mut automaton.graph);
+        }
+
+        // Moving by hand updates the nodes as the value is dragged, and is
+        // recorded as one edit once it's let go.
+        if moved.length() > 0.0 {
+            for id in selected {
+                automaton.graph[*id].position += moved;
+            }
+            self.moved += moved;
+        }
+        if released && self.moved.length() > 0.0 {
+            history.push(Edit::MoveNodes {
+                nodes: selected.to_vec(),
+                offset: self.moved,
+            });
+            self.moved = Vector2::zero();
+        }
+    }
+}
+
+/// The value shared by all of `values`, or `None` if they differ.
+fn common<T: PartialEq>(mut values: impl Iterator<Item = T>) -> Option<T> {
+    let first = values.next()?;
+    values.all(|value| value == first).then_some(first)
+}
+
+/// Shows "mixed" in place of the value of `drag_value` if `is_mixed`.
+fn mixed(drag_value: DragValue, is_mixed: bool) -> DragValue {
+    if is_mixed {
+        drag_value.custom_formatter(|_, _| MIXED.to_string())
+    } else {
+        drag_value
+    }
+}
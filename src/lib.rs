@@ -0,0 +1,28 @@
+//! Cellular automata on arbitrary directed graphs, where nodes can play notes.
+//!
+//! The engine is independent of the window and editor, which live in the
+//! `cellgraph` binary behind the default `gui` feature.
+
+pub mod automaton;
+pub mod cellang;
+pub mod clock;
+pub mod graph;
+pub mod history;
+pub mod midi;
+pub mod note;
+pub mod osc;
+pub mod rng;
+pub mod saved_state;
+pub mod smf;
+pub mod synth;
+pub mod tuning;
+pub mod vec2;
+pub mod wav;
+
+pub use automaton::{
+    Automaton, BoolPattern, Case, IntExpr, Ruleset, RunOutcome, Snapshot, StateChange, UpdateMode,
+};
+pub use graph::{Graph, Node, NodeId, State};
+pub use note::Note;
+pub use saved_state::SavedState;
+pub use vec2::Vector2;
@@ -1,18 +1,43 @@
+#[cfg(feature = "gui")]
 mod app;
-mod automaton;
-mod cellang;
-mod graph;
-mod note;
-mod saved_state;
-mod vec2;
+mod headless;
+#[cfg(feature = "gui")]
+mod inspector;
+
+#[cfg(feature = "gui")]
 use std::collections::HashMap;
 
+#[cfg(feature = "gui")]
 use app::App;
-use automaton::Automaton;
-use graph::Graph;
+#[cfg(feature = "gui")]
+use cellgraph::{Automaton, Graph};
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match args.first().map(String::as_str) {
+        Some("run") => Some(headless::run(&args[1..])),
+        Some("render") => Some(headless::render(&args[1..])),
+        _ => None,
+    };
+    if let Some(result) = result {
+        if let Err(error) = result {
+            eprintln!("{error}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    #[cfg(feature = "gui")]
+    macroquad::Window::new("cell sound", gui());
+    #[cfg(not(feature = "gui"))]
+    {
+        eprintln!("cellgraph was built without the gui feature; use `cellgraph run` or `cellgraph render`");
+        std::process::exit(1);
+    }
+}
 
-#[macroquad::main("cell sound")]
-async fn main() {
+#[cfg(feature = "gui")]
+async fn gui() {
     let graph = Graph::new();
     let rule_map = HashMap::new();
     let mut app = App::new(Automaton::new(rule_map, graph));
@@ -0,0 +1,713 @@
+use std::collections::BTreeSet;
+
+use crate::{
+    automaton::{Automaton, IntExpr, StateChange},
+    cellang,
+    graph::{Graph, NodeId, State},
+    note::{Note, NoteEvent},
+    rng::Rng,
+    tuning::Tuning,
+};
+
+/// Somewhere raw MIDI messages can be sent to.
+pub trait MidiSink {
+    fn send(&mut self, message: &[u8]);
+
+    /// Takes the last error from sending messages, for sinks that can fail
+    /// without the sender noticing, like ports of the operating system.
+    fn take_error(&mut self) -> Option<String> {
+        None
+    }
+}
+
+/// The velocity of notes that don't set one, and of notes whose velocity
+/// expression doesn't parse.
+const DEFAULT_VELOCITY: u8 = 60;
+
+/// How a node's note is sent.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct MidiSettings {
+    /// From 0 to 15, shown as 1 to 16.
+    pub channel: u8,
+    pub velocity: Velocity,
+    /// How many steps the note lasts. `None` holds it until the node turns
+    /// off.
+    pub duration: Option<usize>,
+    /// Whether the node turns on while any key is held on a MIDI keyboard,
+    /// whatever its note.
+    #[serde(default)]
+    pub input: bool,
+}
+
+impl Default for MidiSettings {
+    fn default() -> Self {
+        Self {
+            channel: 0,
+            velocity: Velocity::Fixed(DEFAULT_VELOCITY),
+            duration: None,
+            input: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum Velocity {
+    Fixed(u8),
+    /// An arithmetic expression of the rule language, like `on * 20`,
+    /// evaluated for the node when it turns on.
+    Expression(VelocityExpression),
+}
+
+/// The text of a velocity expression, parsed once when it is set. Saved as
+/// just the text.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(from = "String", into = "String")]
+pub struct VelocityExpression {
+    text: String,
+    /// `None` if the text doesn't parse.
+    parsed: Option<IntExpr>,
+}
+
+impl VelocityExpression {
+    pub fn new(text: &str) -> Self {
+        Self {
+            text: text.to_string(),
+            parsed: cellang::expr_parser::arithmetic(text).ok(),
+        }
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+}
+
+impl PartialEq for VelocityExpression {
+    fn eq(&self, other: &Self) -> bool {
+        self.text == other.text
+    }
+}
+
+impl From<String> for VelocityExpression {
+    fn from(text: String) -> Self {
+        Self::new(&text)
+    }
+}
+
+impl From<VelocityExpression> for String {
+    fn from(expression: VelocityExpression) -> Self {
+        expression.text
+    }
+}
+
+/// A note that has been turned on and not yet off.
+#[derive(Clone, Debug)]
+struct Sounding {
+    node: NodeId,
+    channel: u8,
+    number: u8,
+    /// Steps until a note with a fixed duration ends.
+    steps_left: Option<usize>,
+}
+
+/// Turns the state changes of each step into MIDI messages, and ends notes
+/// with a fixed duration.
+#[derive(Clone, Debug, Default)]
+pub struct Player {
+    sounding: Vec<Sounding>,
+    /// Used by `random` in velocity expressions, so they don't change the
+    /// automaton's own random numbers.
+    rng: Rng,
+}
+
+impl Player {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sends a note-on for every node with a note that turned on, and a
+    /// note-off for every one that turned off or whose duration has passed.
+    /// Call once per step.
+    ///
+    /// All note-offs of a step are sent before its note-ons, so a note ending
+    /// on the same pitch another one starts doesn't cut the new one short.
+    pub fn play(
+        &mut self,
+        changes: &[StateChange],
+        automaton: &Automaton,
+        tuning: &Tuning,
+        sink: &mut dyn MidiSink,
+    ) {
+        self.sounding
+            .retain_mut(|sounding| match &mut sounding.steps_left {
+                Some(steps_left) => {
+                    *steps_left = steps_left.saturating_sub(1);
+                    if *steps_left == 0 {
+                        note_off(sounding.channel, sounding.number, sink);
+                    }
+                    *steps_left > 0
+                }
+                None => true,
+            });
+
+        let changes: Vec<&StateChange> = changes
+            .iter()
+            .filter(|change| automaton.graph[change.node].note.is_some())
+            .collect();
+        for change in &changes {
+            let held = automaton.graph[change.node].midi.duration.is_none();
+            if change.turned_on() || (change.turned_off() && held) {
+                self.end(change.node, sink);
+            }
+        }
+        for change in changes.iter().filter(|change| change.turned_on()) {
+            let node = &automaton.graph[change.node];
+            let Some(note) = &node.note else {
+                continue;
+            };
+            let velocity = self.velocity(change.node, automaton);
+            if let Some(number) = note_on(note, tuning, node.midi.channel, velocity, sink) {
+                self.sounding.push(Sounding {
+                    node: change.node,
+                    channel: node.midi.channel,
+                    number,
+                    steps_left: node.midi.duration,
+                });
+            }
+        }
+    }
+
+    /// Sends a note-off for every note still sounding.
+    pub fn stop(&mut self, sink: &mut dyn MidiSink) {
+        for sounding in self.sounding.drain(..) {
+            note_off(sounding.channel, sounding.number, sink);
+        }
+    }
+
+    /// Ends the note of `node` if it is sounding.
+    fn end(&mut self, node: NodeId, sink: &mut dyn MidiSink) {
+        self.sounding.retain(|sounding| {
+            if sounding.node == node {
+                note_off(sounding.channel, sounding.number, sink);
+            }
+            sounding.node != node
+        });
+    }
+
+    fn velocity(&mut self, node: NodeId, automaton: &Automaton) -> u8 {
+        match &automaton.graph[node].midi.velocity {
+            Velocity::Fixed(velocity) => *velocity,
+            Velocity::Expression(expression) => match &expression.parsed {
+                Some(parsed) => parsed
+                    .calculate(node, &automaton.graph, automaton.history(), &mut self.rng)
+                    .clamp(1, 127) as u8,
+                None => DEFAULT_VELOCITY,
+            },
+        }
+    }
+}
+
+/// Starts playing `note` on `channel` and returns the MIDI note number it was
+/// played as. Unless `tuning` is standard, the note-on is preceded by a pitch
+/// bend, which applies to every note sounding on the channel.
+pub fn note_on(
+    note: &Note,
+    tuning: &Tuning,
+    channel: u8,
+    velocity: u8,
+    sink: &mut dyn MidiSink,
+) -> Option<u8> {
+    let (number, bend) = tuning.midi_pitch(note)?;
+    if !tuning.is_standard() {
+        sink.send(&channel_message(
+            channel,
+            midi_msg::ChannelVoiceMsg::PitchBend { bend },
+        ));
+    }
+    sink.send(&channel_message(
+        channel,
+        midi_msg::ChannelVoiceMsg::NoteOn {
+            note: number,
+            velocity,
+        },
+    ));
+    Some(number)
+}
+
+pub fn note_off(channel: u8, number: u8, sink: &mut dyn MidiSink) {
+    sink.send(&channel_message(
+        channel,
+        midi_msg::ChannelVoiceMsg::NoteOff {
+            note: number,
+            velocity: 0,
+        },
+    ));
+}
+
+fn channel_message(channel: u8, msg: midi_msg::ChannelVoiceMsg) -> Vec<u8> {
+    midi_msg::MidiMsg::ChannelVoice {
+        channel: midi_msg::Channel::from_u8(channel),
+        msg,
+    }
+    .to_midi()
+}
+
+/// Somewhere raw MIDI messages come from.
+pub trait MidiSource {
+    /// Takes the messages that arrived since the last call.
+    fn receive(&mut self) -> Vec<Vec<u8>>;
+}
+
+/// Sets the states of nodes from the keys played on a MIDI keyboard.
+#[derive(Clone, Debug, Default)]
+pub struct NoteInput {
+    /// The MIDI note numbers of the keys held down.
+    held: BTreeSet<u8>,
+}
+
+impl NoteInput {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Turns on the nodes whose note is on a pressed key and turns off the
+    /// ones whose key is released. Input nodes are on while any key is held.
+    pub fn handle(&mut self, event: NoteEvent, graph: &mut Graph) {
+        let (number, state): (u8, State) = match event {
+            NoteEvent::On { number, .. } => {
+                self.held.insert(number);
+                (number, 1)
+            }
+            NoteEvent::Off { number, .. } => {
+                self.held.remove(&number);
+                (number, 0)
+            }
+        };
+        let any_held = !self.held.is_empty();
+        for node in graph.nodes.values_mut() {
+            if node.midi.input {
+                node.write = any_held as State;
+            } else if node
+                .note
+                .as_ref()
+                .is_some_and(|note| note.to_midi_number() == Some(number))
+            {
+                node.write = state;
+            }
+        }
+    }
+
+    /// Handles every note event among `messages` and ignores the rest.
+    pub fn handle_messages(&mut self, messages: &[Vec<u8>], graph: &mut Graph) {
+        for event in messages
+            .iter()
+            .filter_map(|message| NoteEvent::decode(message))
+        {
+            self.handle(event, graph);
+        }
+    }
+}
+
+/// Hands out messages queued with [`FakeMidiSource::push`], standing in for
+/// a keyboard.
+#[derive(Default, Debug, Clone)]
+pub struct FakeMidiSource {
+    pub messages: Vec<Vec<u8>>,
+}
+
+impl FakeMidiSource {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, message: &[u8]) {
+        self.messages.push(message.to_vec());
+    }
+}
+
+impl MidiSource for FakeMidiSource {
+    fn receive(&mut self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut self.messages)
+    }
+}
+
+/// Keeps every message in memory instead of playing it.
+#[derive(Default, Debug, Clone)]
+pub struct MidiRecorder {
+    pub messages: Vec<Vec<u8>>,
+}
+
+impl MidiRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl MidiSink for MidiRecorder {
+    fn send(&mut self, message: &[u8]) {
+        self.messages.push(message.to_vec());
+    }
+}
+
+/// A connection to a MIDI output port of the operating system.
+#[cfg(feature = "midi-port")]
+pub struct MidiPort {
+    connection: midir::MidiOutputConnection,
+    error: Option<String>,
+}
+
+#[cfg(feature = "midi-port")]
+impl MidiPort {
+    /// Lists the names of the available output ports.
+    pub fn port_names() -> Result<Vec<String>, String> {
+        let output = midir::MidiOutput::new("cellgraph").map_err(|e| e.to_string())?;
+        Ok(output
+            .ports()
+            .iter()
+            .filter_map(|port| output.port_name(port).ok())
+            .collect())
+    }
+
+    /// Connects to the port called `name`, or to the first port if no name is
+    /// given.
+    pub fn connect(name: Option<&str>) -> Result<Self, String> {
+        let output = midir::MidiOutput::new("cellgraph").map_err(|e| e.to_string())?;
+        let ports = output.ports();
+        let port = match name {
+            Some(name) => ports
+                .iter()
+                .find(|port| output.port_name(port).is_ok_and(|port| port == name)),
+            None => ports.first(),
+        }
+        .ok_or_else(|| "no midi output port found".to_string())?;
+        let connection = output
+            .connect(port, "cellgraph")
+            .map_err(|e| e.to_string())?;
+        Ok(Self {
+            connection,
+            error: None,
+        })
+    }
+}
+
+#[cfg(feature = "midi-port")]
+impl MidiSink for MidiPort {
+    fn send(&mut self, message: &[u8]) {
+        if let Err(error) = self.connection.send(message) {
+            self.error = Some(error.to_string());
+        }
+    }
+
+    fn take_error(&mut self) -> Option<String> {
+        self.error.take()
+    }
+}
+
+/// A connection to a MIDI input port of the operating system.
+#[cfg(feature = "midi-port")]
+pub struct MidiInputPort {
+    // Closes the connection when dropped.
+    _connection: midir::MidiInputConnection<()>,
+    messages: std::sync::mpsc::Receiver<Vec<u8>>,
+}
+
+#[cfg(feature = "midi-port")]
+impl MidiInputPort {
+    /// Lists the names of the available input ports.
+    pub fn port_names() -> Result<Vec<String>, String> {
+        let input = midir::MidiInput::new("cellgraph").map_err(|e| e.to_string())?;
+        Ok(input
+            .ports()
+            .iter()
+            .filter_map(|port| input.port_name(port).ok())
+            .collect())
+    }
+
+    /// Connects to the input port called `name`.
+    pub fn connect(name: &str) -> Result<Self, String> {
+        let input = midir::MidiInput::new("cellgraph").map_err(|e| e.to_string())?;
+        let ports = input.ports();
+        let port = ports
+            .iter()
+            .find(|port| input.port_name(port).is_ok_and(|port| port == name))
+            .ok_or_else(|| format!("no midi input port called {name}"))?;
+        let (sender, messages) = std::sync::mpsc::channel();
+        let connection = input
+            .connect(
+                port,
+                "cellgraph",
+                move |_, message, _| {
+                    // The receiver is only gone once the port is dropped.
+                    let _ = sender.send(message.to_vec());
+                },
+                (),
+            )
+            .map_err(|e| e.to_string())?;
+        Ok(Self {
+            _connection: connection,
+            messages,
+        })
+    }
+}
+
+#[cfg(feature = "midi-port")]
+impl MidiSource for MidiInputPort {
+    fn receive(&mut self) -> Vec<Vec<u8>> {
+        self.messages.try_iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        graph::Node,
+        note::{Accidental, NoteLetter},
+        vec2::Vector2,
+    };
+
+    const NOTE_ON: [u8; 3] = [0x90, 60, DEFAULT_VELOCITY];
+    const NOTE_OFF: [u8; 3] = [0x80, 60, 0];
+
+    /// An automaton running `code` with a node for each `(state, ruleset,
+    /// sources)`, every one playing C4.
+    fn automaton(code: &str, nodes: &[(State, &str, &[usize])]) -> Automaton {
+        let mut automaton = crate::automaton::tests::automaton(code, nodes);
+        for node in automaton.graph.nodes.values_mut() {
+            node.note = Some(Note::new(NoteLetter::C, Accidental::Neutral, 4));
+        }
+        automaton
+    }
+
+    /// Steps `automaton` `steps` times and returns the messages of each step.
+    fn play(automaton: &mut Automaton, steps: usize) -> Vec<Vec<Vec<u8>>> {
+        let mut player = Player::new();
+        (0..steps)
+            .map(|_| {
+                let mut recorder = MidiRecorder::new();
+                let changes = automaton.step();
+                player.play(&changes, automaton, &Tuning::default(), &mut recorder);
+                recorder.messages
+            })
+            .collect()
+    }
+
+    #[test]
+    fn notes_follow_the_node_state() {
+        let mut automaton = automaton("blink state = 0: on", &[(0, "blink", &[])]);
+        assert_eq!(
+            play(&mut automaton, 3),
+            [
+                vec![NOTE_ON.to_vec()],
+                vec![NOTE_OFF.to_vec()],
+                vec![NOTE_ON.to_vec()]
+            ]
+        );
+    }
+
+    #[test]
+    fn notes_use_the_node_channel_and_velocity() {
+        let mut automaton = automaton("blink state = 0: on", &[(0, "blink", &[])]);
+        automaton.graph[NodeId(0)].midi.channel = 2;
+        automaton.graph[NodeId(0)].midi.velocity = Velocity::Fixed(100);
+        assert_eq!(
+            play(&mut automaton, 2),
+            [vec![vec![0x92, 60, 100]], vec![vec![0x82, 60, 0]]]
+        );
+    }
+
+    #[test]
+    fn nodes_without_a_rule_stay_quiet() {
+        let mut automaton = automaton("up state >= 0: on", &[(1, "none", &[]), (0, "none", &[])]);
+        assert!(play(&mut automaton, 4).iter().all(Vec::is_empty));
+        assert_eq!(automaton.graph[NodeId(0)].write, 1);
+    }
+
+    #[test]
+    fn fixed_duration_notes_end_on_their_own() {
+        let mut automaton = automaton("up state >= 0: on", &[(0, "up", &[])]);
+        automaton.graph[NodeId(0)].midi.duration = Some(2);
+        assert_eq!(
+            play(&mut automaton, 4),
+            [
+                vec![NOTE_ON.to_vec()],
+                vec![],
+                vec![NOTE_OFF.to_vec()],
+                vec![]
+            ]
+        );
+    }
+
+    #[test]
+    fn fixed_duration_notes_are_retriggered() {
+        let mut automaton = automaton("blink state = 0: on", &[(0, "blink", &[])]);
+        automaton.graph[NodeId(0)].midi.duration = Some(3);
+        assert_eq!(
+            play(&mut automaton, 3),
+            [
+                vec![NOTE_ON.to_vec()],
+                vec![],
+                vec![NOTE_OFF.to_vec(), NOTE_ON.to_vec()]
+            ]
+        );
+    }
+
+    #[test]
+    fn note_offs_come_before_note_ons() {
+        // Two nodes on the same pitch taking turns.
+        let mut automaton = automaton("copy on > 0: on", &[(1, "copy", &[1]), (0, "copy", &[0])]);
+        assert_eq!(
+            play(&mut automaton, 3),
+            [
+                vec![NOTE_ON.to_vec()],
+                vec![NOTE_OFF.to_vec(), NOTE_ON.to_vec()],
+                vec![NOTE_OFF.to_vec(), NOTE_ON.to_vec()]
+            ]
+        );
+    }
+
+    #[test]
+    fn expired_notes_end_before_note_ons() {
+        let mut automaton = automaton("copy on > 0: on", &[(1, "copy", &[1]), (0, "copy", &[0])]);
+        automaton.graph[NodeId(1)].midi.duration = Some(1);
+        assert_eq!(
+            play(&mut automaton, 2)[1],
+            [NOTE_OFF.to_vec(), NOTE_ON.to_vec()]
+        );
+    }
+
+    /// The velocity of the first note of a node with `expression` whose two
+    /// inputs are always on.
+    fn expression_velocity(expression: &str) -> u8 {
+        let mut automaton = automaton(
+            "up state >= 0: on\nblink state = 0: on",
+            &[(1, "up", &[]), (1, "up", &[]), (0, "blink", &[0, 1])],
+        );
+        automaton.graph[NodeId(0)].note = None;
+        automaton.graph[NodeId(1)].note = None;
+        automaton.graph[NodeId(2)].midi.velocity =
+            Velocity::Expression(VelocityExpression::new(expression));
+        play(&mut automaton, 1)[0][0][2]
+    }
+
+    #[test]
+    fn velocity_expressions_are_evaluated_for_the_node() {
+        assert_eq!(expression_velocity("on * 20 + 3"), 43);
+        assert_eq!(expression_velocity("on * 100"), 127);
+        assert_eq!(expression_velocity("60 / off"), 1);
+        assert_eq!(expression_velocity("on *"), DEFAULT_VELOCITY);
+    }
+
+    #[test]
+    fn velocity_expressions_are_saved_as_text() {
+        let velocity = Velocity::Expression(VelocityExpression::new("on * 20"));
+        let text = serde_json::to_string(&velocity).unwrap();
+        assert_eq!(text, r#"{"Expression":"on * 20"}"#);
+        assert_eq!(serde_json::from_str::<Velocity>(&text).unwrap(), velocity);
+    }
+
+    /// A graph with a node on C4, one on D4 and an input node without a note.
+    fn keyboard_graph() -> Graph {
+        let mut graph = Graph::new();
+        for letter in [NoteLetter::C, NoteLetter::D] {
+            let mut node = Node::new(0, 0, vec![], Vector2::zero(), "keys".to_string());
+            node.note = Some(Note::new(letter, Accidental::Neutral, 4));
+            graph.add_node(node);
+        }
+        let mut input = Node::new(0, 0, vec![], Vector2::zero(), "keys".to_string());
+        input.midi.input = true;
+        graph.add_node(input);
+        graph
+    }
+
+    /// Hands the messages pushed to `source` to `input` and returns the
+    /// states of the nodes.
+    fn receive(
+        source: &mut FakeMidiSource,
+        input: &mut NoteInput,
+        graph: &mut Graph,
+    ) -> Vec<State> {
+        input.handle_messages(&source.receive(), graph);
+        states(graph)
+    }
+
+    fn states(graph: &Graph) -> Vec<State> {
+        graph.nodes.values().map(|node| node.write).collect()
+    }
+
+    #[test]
+    fn keys_turn_nodes_on_and_off() {
+        let mut graph = keyboard_graph();
+        let mut source = FakeMidiSource::new();
+        let mut input = NoteInput::new();
+
+        source.push(&[0x90, 60, 100]);
+        assert_eq!(receive(&mut source, &mut input, &mut graph), [1, 0, 1]);
+        source.push(&[0x91, 62, 100]);
+        assert_eq!(receive(&mut source, &mut input, &mut graph), [1, 1, 1]);
+        source.push(&[0x80, 60, 0]);
+        assert_eq!(receive(&mut source, &mut input, &mut graph), [0, 1, 1]);
+        // A note-on with velocity 0 is a note-off.
+        source.push(&[0x90, 62, 0]);
+        assert_eq!(receive(&mut source, &mut input, &mut graph), [0, 0, 0]);
+        assert!(source.receive().is_empty());
+    }
+
+    #[test]
+    fn input_nodes_stay_on_while_any_key_is_held() {
+        let mut graph = keyboard_graph();
+        let mut source = FakeMidiSource::new();
+        let mut input = NoteInput::new();
+
+        source.push(&[0x90, 64, 100]);
+        source.push(&[0x90, 65, 100]);
+        source.push(&[0x80, 64, 0]);
+        assert_eq!(receive(&mut source, &mut input, &mut graph), [0, 0, 1]);
+        source.push(&[0x90, 65, 0]);
+        assert_eq!(receive(&mut source, &mut input, &mut graph), [0, 0, 0]);
+    }
+
+    #[test]
+    fn held_keys_keep_input_nodes_on_while_stepping() {
+        // An input node without a rule, followed by a node copying it.
+        let mut automaton = crate::automaton::tests::automaton(
+            "copy on > 0: on",
+            &[(0, "none", &[]), (0, "copy", &[0])],
+        );
+        automaton.graph[NodeId(0)].midi.input = true;
+        let mut source = FakeMidiSource::new();
+        let mut input = NoteInput::new();
+
+        source.push(&[0x90, 60, 100]);
+        input.handle_messages(&source.receive(), &mut automaton.graph);
+        for _ in 0..4 {
+            automaton.step();
+            assert_eq!(states(&automaton.graph), [1, 1]);
+        }
+        source.push(&[0x80, 60, 0]);
+        input.handle_messages(&source.receive(), &mut automaton.graph);
+        automaton.step();
+        assert_eq!(states(&automaton.graph), [0, 0]);
+    }
+
+    #[test]
+    fn other_messages_are_ignored() {
+        let mut graph = keyboard_graph();
+        let mut source = FakeMidiSource::new();
+        let mut input = NoteInput::new();
+
+        source.push(&[0xb0, 64, 127]);
+        source.push(&[0xf8]);
+        assert_eq!(receive(&mut source, &mut input, &mut graph), [0, 0, 0]);
+    }
+
+    #[test]
+    fn stop_ends_every_sounding_note() {
+        let mut automaton = automaton("up state >= 0: on", &[(0, "up", &[])]);
+        let mut player = Player::new();
+        let mut recorder = MidiRecorder::new();
+        let changes = automaton.step();
+        player.play(&changes, &automaton, &Tuning::default(), &mut recorder);
+        player.stop(&mut recorder);
+        assert_eq!(recorder.messages, [NOTE_ON.to_vec(), NOTE_OFF.to_vec()]);
+    }
+}
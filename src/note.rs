@@ -1,8 +1,7 @@
-use midi_msg;
-use std::fmt::Display;
+use std::{fmt::Display, str::FromStr};
 
-#[derive(Clone, Debug, Copy, serde::Serialize, serde::Deserialize)]
-enum NoteLetter {
+#[derive(Clone, Debug, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum NoteLetter {
     C = 0,
     D = 2,
     E = 4,
@@ -11,18 +10,44 @@ enum NoteLetter {
     A = 9,
     B = 11,
 }
-#[derive(Clone, Copy, Debug, serde::Serialize, serde::Deserialize)]
-enum Accidental {
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum Accidental {
+    DoubleFlat = -2,
     Flat = -1,
     Neutral = 0,
     Sharp = 1,
+    DoubleSharp = 2,
 }
 
-#[derive(Clone, serde::Serialize, serde::Deserialize)]
+impl NoteLetter {
+    pub const ALL: [NoteLetter; 7] = [
+        NoteLetter::C,
+        NoteLetter::D,
+        NoteLetter::E,
+        NoteLetter::F,
+        NoteLetter::G,
+        NoteLetter::A,
+        NoteLetter::B,
+    ];
+}
+
+impl Accidental {
+    pub const ALL: [Accidental; 5] = [
+        Accidental::DoubleFlat,
+        Accidental::Flat,
+        Accidental::Neutral,
+        Accidental::Sharp,
+        Accidental::DoubleSharp,
+    ];
+}
+
+/// A pitch in scientific pitch notation, where octaves start at C and C4 is
+/// middle C.
+#[derive(Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub struct Note {
-    letter: NoteLetter,
-    accidental: Accidental,
-    octave: u8,
+    pub letter: NoteLetter,
+    pub accidental: Accidental,
+    pub octave: i8,
 }
 
 impl Display for Note {
@@ -38,9 +63,11 @@ impl Display for Note {
         };
         write!(f, "{}", note)?;
         match self.accidental {
+            Accidental::DoubleFlat => write!(f, "bb")?,
             Accidental::Flat => write!(f, "b")?,
             Accidental::Neutral => (),
             Accidental::Sharp => write!(f, "#")?,
+            Accidental::DoubleSharp => write!(f, "##")?,
         };
         write!(f, "{}", self.octave)?;
         Ok(())
@@ -48,48 +75,245 @@ impl Display for Note {
 }
 impl std::fmt::Debug for Note {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let note = match self.letter {
-            NoteLetter::C => 'C',
-            NoteLetter::D => 'D',
-            NoteLetter::E => 'E',
-            NoteLetter::F => 'F',
-            NoteLetter::G => 'G',
-            NoteLetter::A => 'A',
-            NoteLetter::B => 'B',
+        Display::fmt(self, f)
+    }
+}
+
+/// Why a string isn't a note.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseNoteError {
+    /// The string doesn't start with a letter from A to G.
+    Letter,
+    /// What follows the letter and accidental isn't an octave number.
+    Octave(String),
+}
+
+impl Display for ParseNoteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseNoteError::Letter => write!(f, "a note starts with a letter from A to G"),
+            ParseNoteError::Octave(octave) => write!(f, "invalid octave {octave:?}"),
+        }
+    }
+}
+
+impl std::error::Error for ParseNoteError {}
+
+impl FromStr for Note {
+    type Err = ParseNoteError;
+
+    /// Parses a letter, upper or lower case, followed by an optional
+    /// accidental (`#`, `##`, `b` or `bb`) and an octave, e.g. `C#4`, `Eb3` or
+    /// `Gbb-1`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut chars = s.chars();
+        let letter = match chars.next().map(|c| c.to_ascii_uppercase()) {
+            Some('C') => NoteLetter::C,
+            Some('D') => NoteLetter::D,
+            Some('E') => NoteLetter::E,
+            Some('F') => NoteLetter::F,
+            Some('G') => NoteLetter::G,
+            Some('A') => NoteLetter::A,
+            Some('B') => NoteLetter::B,
+            _ => return Err(ParseNoteError::Letter),
         };
-        write!(f, "{}", note)?;
-        match self.accidental {
-            Accidental::Flat => write!(f, "b")?,
-            Accidental::Neutral => (),
-            Accidental::Sharp => write!(f, "#")?,
+        let rest = chars.as_str();
+        let (accidental, octave) = if let Some(octave) = rest.strip_prefix("##") {
+            (Accidental::DoubleSharp, octave)
+        } else if let Some(octave) = rest.strip_prefix('#') {
+            (Accidental::Sharp, octave)
+        } else if let Some(octave) = rest.strip_prefix("bb") {
+            (Accidental::DoubleFlat, octave)
+        } else if let Some(octave) = rest.strip_prefix('b') {
+            (Accidental::Flat, octave)
+        } else {
+            (Accidental::Neutral, rest)
         };
-        write!(f, "{}", self.octave)?;
-        Ok(())
+        // `parse` would also take a leading `+`, which `Display` never writes.
+        let parsed = if octave.starts_with('+') {
+            None
+        } else {
+            octave.parse().ok()
+        };
+        let octave = parsed.ok_or_else(|| ParseNoteError::Octave(octave.to_string()))?;
+        Ok(Note::new(letter, accidental, octave))
     }
 }
 
 impl Note {
-    fn to_midi_number(&self) -> u8 {
-        24 + self.letter as u8 + self.octave * 12
+    pub fn new(letter: NoteLetter, accidental: Accidental, octave: i8) -> Self {
+        Self {
+            letter,
+            accidental,
+            octave,
+        }
+    }
+
+    /// The note with the given MIDI note number, where 60 is C4. Black keys
+    /// are spelled with sharps.
+    pub fn from_midi_number(number: u8) -> Self {
+        let (letter, accidental) = match number % 12 {
+            0 => (NoteLetter::C, Accidental::Neutral),
+            1 => (NoteLetter::C, Accidental::Sharp),
+            2 => (NoteLetter::D, Accidental::Neutral),
+            3 => (NoteLetter::D, Accidental::Sharp),
+            4 => (NoteLetter::E, Accidental::Neutral),
+            5 => (NoteLetter::F, Accidental::Neutral),
+            6 => (NoteLetter::F, Accidental::Sharp),
+            7 => (NoteLetter::G, Accidental::Neutral),
+            8 => (NoteLetter::G, Accidental::Sharp),
+            9 => (NoteLetter::A, Accidental::Neutral),
+            10 => (NoteLetter::A, Accidental::Sharp),
+            _ => (NoteLetter::B, Accidental::Neutral),
+        };
+        Self::new(letter, accidental, (number / 12) as i8 - 1)
+    }
+
+    /// The equal tempered note closest to `frequency` in Hz, with A4 at 440
+    /// Hz, or `None` if that note has no MIDI note number.
+    pub fn from_frequency(frequency: f64) -> Option<Self> {
+        let number = (69.0 + 12.0 * (frequency / 440.0).log2()).round();
+        (0.0..=127.0)
+            .contains(&number)
+            .then(|| Self::from_midi_number(number as u8))
+    }
+
+    /// The number of semitones above C-1, which matches the MIDI note
+    /// number for notes in the MIDI range.
+    pub fn key(&self) -> i32 {
+        (self.octave as i32 + 1) * 12 + self.letter as i32 + self.accidental as i32
+    }
+
+    /// The MIDI note number, where 60 is C4, or `None` if the note is out of
+    /// the MIDI range.
+    pub fn to_midi_number(&self) -> Option<u8> {
+        u8::try_from(self.key()).ok().filter(|number| *number < 128)
+    }
+}
+
+/// A key pressed or released on a MIDI keyboard.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NoteEvent {
+    On {
+        channel: u8,
+        number: u8,
+        velocity: u8,
+    },
+    Off {
+        channel: u8,
+        number: u8,
+    },
+}
+
+impl NoteEvent {
+    /// Reads a note-on or note-off message, or returns `None` for any other
+    /// message. A note-on with velocity 0 counts as a note-off, as many
+    /// keyboards send those instead.
+    pub fn decode(message: &[u8]) -> Option<Self> {
+        let (midi_msg::MidiMsg::ChannelVoice { channel, msg }, _) =
+            midi_msg::MidiMsg::from_midi(message).ok()?
+        else {
+            return None;
+        };
+        let channel = channel as u8;
+        match msg {
+            midi_msg::ChannelVoiceMsg::NoteOn { note, velocity: 0 }
+            | midi_msg::ChannelVoiceMsg::NoteOff { note, .. } => Some(NoteEvent::Off {
+                channel,
+                number: note,
+            }),
+            midi_msg::ChannelVoiceMsg::NoteOn { note, velocity } => Some(NoteEvent::On {
+                channel,
+                number: note,
+                velocity,
+            }),
+            _ => None,
+        }
     }
-    fn to_midi_on(&self) -> Vec<u8> {
-        midi_msg::MidiMsg::ChannelVoice {
-            channel: midi_msg::Channel::Ch1,
-            msg: midi_msg::ChannelVoiceMsg::NoteOn {
-                note: self.to_midi_number(),
-                velocity: 60,
-            },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_note_round_trips_through_text() {
+        for octave in i8::MIN..=i8::MAX {
+            for letter in NoteLetter::ALL {
+                for accidental in Accidental::ALL {
+                    let note = Note::new(letter, accidental, octave);
+                    assert_eq!(note.to_string().parse::<Note>(), Ok(note));
+                }
+            }
         }
-        .to_midi()
     }
-    fn to_midi_off(&self) -> Vec<u8> {
-        midi_msg::MidiMsg::ChannelVoice {
-            channel: midi_msg::Channel::Ch1,
-            msg: midi_msg::ChannelVoiceMsg::NoteOff {
-                note: self.to_midi_number(),
-                velocity: 0,
-            },
+
+    #[test]
+    fn notes_are_written_in_scientific_pitch_notation() {
+        let text = |letter, accidental, octave| Note::new(letter, accidental, octave).to_string();
+        assert_eq!(text(NoteLetter::C, Accidental::Sharp, 4), "C#4");
+        assert_eq!(text(NoteLetter::E, Accidental::Flat, 3), "Eb3");
+        assert_eq!(text(NoteLetter::G, Accidental::DoubleFlat, -1), "Gbb-1");
+        assert_eq!(text(NoteLetter::F, Accidental::DoubleSharp, 10), "F##10");
+        assert_eq!(
+            "a4".parse(),
+            Ok(Note::new(NoteLetter::A, Accidental::Neutral, 4))
+        );
+    }
+
+    #[test]
+    fn bad_notes_are_rejected() {
+        for text in ["", "H4", "#4", "4", "z4"] {
+            assert_eq!(text.parse::<Note>(), Err(ParseNoteError::Letter), "{text}");
+        }
+        for (text, octave) in [
+            ("C", ""),
+            ("C#", ""),
+            ("C###4", "#4"),
+            ("Cbbb4", "b4"),
+            ("C+4", "+4"),
+            ("C4.5", "4.5"),
+            ("C 4", " 4"),
+            ("C128", "128"),
+            ("C-129", "-129"),
+        ] {
+            assert_eq!(
+                text.parse::<Note>(),
+                Err(ParseNoteError::Octave(octave.to_string())),
+                "{text}"
+            );
         }
-        .to_midi()
+    }
+
+    #[test]
+    fn midi_numbers_round_trip() {
+        for number in 0..=127 {
+            assert_eq!(
+                Note::from_midi_number(number).to_midi_number(),
+                Some(number)
+            );
+        }
+        assert_eq!(Note::from_midi_number(60).to_string(), "C4");
+        assert_eq!(Note::from_midi_number(70).to_string(), "A#4");
+        assert_eq!(Note::from_midi_number(0).to_string(), "C-1");
+        assert_eq!(Note::from_midi_number(127).to_string(), "G9");
+    }
+
+    #[test]
+    fn frequencies_round_to_the_nearest_note() {
+        let name = |frequency| Note::from_frequency(frequency).map(|note| note.to_string());
+        assert_eq!(name(440.0).as_deref(), Some("A4"));
+        assert_eq!(name(261.63).as_deref(), Some("C4"));
+        // 49 cents above A4 is still A4, 51 cents above is A#4.
+        assert_eq!(name(452.6).as_deref(), Some("A4"));
+        assert_eq!(name(453.1).as_deref(), Some("A#4"));
+        // 49 cents below is also A4.
+        assert_eq!(name(427.8).as_deref(), Some("A4"));
+        assert_eq!(name(8.18).as_deref(), Some("C-1"));
+        assert_eq!(name(12543.9).as_deref(), Some("G9"));
+        assert_eq!(name(7.0), None);
+        assert_eq!(name(14000.0), None);
+        assert_eq!(name(0.0), None);
+        assert_eq!(name(-440.0), None);
     }
 }
@@ -0,0 +1,276 @@
+use std::{io, net::UdpSocket};
+
+use crate::{automaton::StateChange, graph::Graph, tuning::Tuning};
+
+/// Where OSC messages go and which addresses they use.
+///
+/// The node address may contain `{id}` and `{ruleset}`, which are replaced by
+/// the node's id and ruleset, e.g. `/synth/{ruleset}/node`.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct OscSettings {
+    /// A host and port, like `127.0.0.1:57120`.
+    pub target: String,
+    pub node_address: String,
+    pub step_address: String,
+}
+
+impl Default for OscSettings {
+    fn default() -> Self {
+        Self {
+            target: "127.0.0.1:57120".to_string(),
+            node_address: "/cellgraph/node".to_string(),
+            step_address: "/cellgraph/step".to_string(),
+        }
+    }
+}
+
+/// An argument of an OSC message.
+#[derive(Clone, Debug, PartialEq)]
+pub enum OscArg {
+    Int(i32),
+    Float(f32),
+    String(String),
+}
+
+/// Encodes an OSC message with `address` and `args`.
+pub fn encode(address: &str, args: &[OscArg]) -> Vec<u8> {
+    let mut message = vec![];
+    write_string(&mut message, address);
+    let tags: String = std::iter::once(',')
+        .chain(args.iter().map(|arg| match arg {
+            OscArg::Int(_) => 'i',
+            OscArg::Float(_) => 'f',
+            OscArg::String(_) => 's',
+        }))
+        .collect();
+    write_string(&mut message, &tags);
+    for arg in args {
+        match arg {
+            OscArg::Int(value) => message.extend(value.to_be_bytes()),
+            OscArg::Float(value) => message.extend(value.to_be_bytes()),
+            OscArg::String(value) => write_string(&mut message, value),
+        }
+    }
+    message
+}
+
+/// Writes `value` followed by at least one zero byte, padded to a multiple of
+/// four bytes.
+fn write_string(message: &mut Vec<u8>, value: &str) {
+    message.extend(value.as_bytes());
+    message.extend(std::iter::repeat_n(0, 4 - value.len() % 4));
+}
+
+/// Sends the state changes of each step as OSC messages over UDP.
+///
+/// Every generation sends `<step address> <n>`, followed by
+/// `<node address> <id> <state>` for each node that changed. Nodes with a note
+/// add the note's name, its MIDI note number and its frequency in Hz.
+pub struct OscSender {
+    socket: UdpSocket,
+    pub settings: OscSettings,
+    /// The number of the last generation sent.
+    generation: usize,
+}
+
+impl OscSender {
+    pub fn connect(settings: OscSettings) -> Result<Self, String> {
+        let socket = UdpSocket::bind("0.0.0.0:0").map_err(|e| e.to_string())?;
+        socket
+            .connect(&settings.target)
+            .map_err(|e| format!("unable to reach {}: {e}", settings.target))?;
+        Ok(Self {
+            socket,
+            settings,
+            generation: 0,
+        })
+    }
+
+    /// Sends the messages for one generation, stopping at the first one that
+    /// can't be sent.
+    pub fn send_step(
+        &mut self,
+        changes: &[StateChange],
+        graph: &Graph,
+        tuning: &Tuning,
+    ) -> io::Result<()> {
+        self.generation += 1;
+        self.send(&encode(
+            &self.settings.step_address,
+            &[OscArg::Int(self.generation as i32)],
+        ))?;
+        for change in changes {
+            let node = &graph[change.node];
+            let address = self
+                .settings
+                .node_address
+                .replace("{id}", &change.node.to_string())
+                .replace("{ruleset}", &node.ruleset);
+            let mut args = vec![
+                OscArg::Int(change.node.0 as i32),
+                OscArg::Int(change.state as i32),
+            ];
+            if let Some(note) = &node.note {
+                args.extend([
+                    OscArg::String(note.to_string()),
+                    OscArg::Int(note.key()),
+                    OscArg::Float(tuning.frequency(note) as f32),
+                ]);
+            }
+            self.send(&encode(&address, &args))?;
+        }
+        Ok(())
+    }
+
+    /// Counts generations from 1 again.
+    pub fn reset(&mut self) {
+        self.generation = 0;
+    }
+
+    fn send(&self, message: &[u8]) -> io::Result<()> {
+        self.socket.send(message).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        automaton::tests::automaton,
+        graph::NodeId,
+        note::{Accidental, Note, NoteLetter},
+    };
+
+    /// Reads a zero-terminated, padded string at the start of `bytes` and
+    /// returns it with the bytes after it.
+    fn read_string(bytes: &[u8]) -> (String, &[u8]) {
+        let length = bytes.iter().position(|&byte| byte == 0).unwrap();
+        let padded = (length / 4 + 1) * 4;
+        assert!(bytes[length..padded].iter().all(|&byte| byte == 0));
+        (
+            String::from_utf8(bytes[..length].to_vec()).unwrap(),
+            &bytes[padded..],
+        )
+    }
+
+    /// Splits an OSC message into its address and arguments.
+    fn decode(message: &[u8]) -> (String, Vec<OscArg>) {
+        let (address, rest) = read_string(message);
+        let (tags, mut rest) = read_string(rest);
+        let args = tags
+            .strip_prefix(',')
+            .unwrap()
+            .chars()
+            .map(|tag| {
+                let arg;
+                (arg, rest) = match tag {
+                    'i' => (
+                        OscArg::Int(i32::from_be_bytes(rest[..4].try_into().unwrap())),
+                        &rest[4..],
+                    ),
+                    'f' => (
+                        OscArg::Float(f32::from_be_bytes(rest[..4].try_into().unwrap())),
+                        &rest[4..],
+                    ),
+                    's' => {
+                        let (value, rest) = read_string(rest);
+                        (OscArg::String(value), rest)
+                    }
+                    _ => panic!("unknown type tag {tag}"),
+                };
+                arg
+            })
+            .collect();
+        assert!(rest.is_empty());
+        (address, args)
+    }
+
+    #[test]
+    fn strings_are_padded_to_four_bytes() {
+        assert_eq!(encode("/abc", &[]), b"/abc\0\0\0\0,\0\0\0");
+        assert_eq!(encode("/ab", &[]), b"/ab\0,\0\0\0");
+        assert_eq!(
+            encode("/a", &[OscArg::String("hello".to_string())]),
+            b"/a\0\0,s\0\0hello\0\0\0"
+        );
+    }
+
+    #[test]
+    fn numbers_are_big_endian() {
+        assert_eq!(
+            encode("/n", &[OscArg::Int(258), OscArg::Float(1.0)]),
+            b"/n\0\0,if\0\0\0\x01\x02\x3f\x80\0\0"
+        );
+    }
+
+    /// Sends one generation of a graph with a node without a note and one
+    /// playing A4, both turning on, to a local socket and returns the
+    /// messages that arrive there.
+    fn send_step(settings: OscSettings) -> Vec<(String, Vec<OscArg>)> {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut sender = OscSender::connect(OscSettings {
+            target: receiver.local_addr().unwrap().to_string(),
+            ..settings
+        })
+        .unwrap();
+
+        let mut automaton = automaton("up state >= 0: on", &[(0, "up", &[]), (0, "up", &[])]);
+        automaton.graph[NodeId(1)].note = Some(Note::new(NoteLetter::A, Accidental::Neutral, 4));
+
+        let changes = automaton.step();
+        sender
+            .send_step(&changes, &automaton.graph, &Tuning::default())
+            .unwrap();
+
+        let mut buffer = [0; 1024];
+        (0..3)
+            .map(|_| {
+                let length = receiver.recv(&mut buffer).unwrap();
+                decode(&buffer[..length])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn steps_are_sent_to_the_target() {
+        assert_eq!(
+            send_step(OscSettings::default()),
+            [
+                ("/cellgraph/step".to_string(), vec![OscArg::Int(1)]),
+                (
+                    "/cellgraph/node".to_string(),
+                    vec![OscArg::Int(0), OscArg::Int(1)]
+                ),
+                (
+                    "/cellgraph/node".to_string(),
+                    vec![
+                        OscArg::Int(1),
+                        OscArg::Int(1),
+                        OscArg::String("A4".to_string()),
+                        OscArg::Int(69),
+                        OscArg::Float(440.0)
+                    ]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn node_addresses_are_filled_in() {
+        let settings = OscSettings {
+            node_address: "/{ruleset}/{id}".to_string(),
+            step_address: "/tick".to_string(),
+            ..OscSettings::default()
+        };
+        let addresses: Vec<_> = send_step(settings)
+            .into_iter()
+            .map(|(address, _)| address)
+            .collect();
+        assert_eq!(addresses, ["/tick", "/up/0", "/up/1"]);
+    }
+}
@@ -1,7 +1,17 @@
-use crate::automaton::Automaton;
+use crate::{
+    automaton::Automaton, clock::Tempo, osc::OscSettings, synth::SynthSettings, tuning::Tuning,
+};
 
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct SavedState {
     pub automaton: Automaton,
     pub code: String,
+    #[serde(default)]
+    pub tempo: Tempo,
+    #[serde(default)]
+    pub tuning: Tuning,
+    #[serde(default)]
+    pub synth: SynthSettings,
+    #[serde(default)]
+    pub osc: OscSettings,
 }
@@ -0,0 +1,205 @@
+use crate::{
+    automaton::Automaton,
+    clock::Tempo,
+    midi::{MidiSink, Player},
+    tuning::Tuning,
+};
+
+/// Resolution of exported files, in ticks per quarter note.
+const TICKS_PER_BEAT: u32 = 480;
+
+/// Collects MIDI messages together with the tick they happened at.
+struct Track {
+    tick: u32,
+    events: Vec<(u32, Vec<u8>)>,
+}
+
+impl MidiSink for Track {
+    fn send(&mut self, message: &[u8]) {
+        self.events.push((self.tick, message.to_vec()));
+    }
+}
+
+/// Runs a copy of `automaton` for `steps` generations at `tempo` and returns
+/// the note events, tuned with `tuning`, as a Standard MIDI File.
+pub fn export(automaton: &Automaton, steps: usize, tempo: &Tempo, tuning: &Tuning) -> Vec<u8> {
+    let tick = |step: usize| {
+        (step as u64 * TICKS_PER_BEAT as u64 / tempo.subdivision.max(1) as u64) as u32
+    };
+    let mut automaton = automaton.clone();
+    let mut track = Track {
+        tick: 0,
+        events: vec![],
+    };
+    let mut player = Player::new();
+
+    for step in 0..steps {
+        track.tick = tick(step);
+        let changes = automaton.step();
+        player.play(&changes, &automaton, tuning, &mut track);
+    }
+
+    track.tick = tick(steps);
+    player.stop(&mut track);
+
+    let mut data = vec![];
+    // The tempo event only has three bytes, which isn't enough for tempos
+    // below about 3.6 bpm. Those are written as the slowest tempo it holds.
+    let micros_per_beat = ((60_000_000.0 / tempo.clamped_bpm()) as u32).min(0xff_ffff);
+    data.extend([0x00, 0xff, 0x51, 0x03]);
+    data.extend(&micros_per_beat.to_be_bytes()[1..]);
+
+    let mut previous = 0;
+    for (tick, message) in track.events {
+        write_variable_length(&mut data, tick - previous);
+        data.extend(message);
+        previous = tick;
+    }
+    data.extend([0x00, 0xff, 0x2f, 0x00]);
+
+    let mut file = vec![];
+    file.extend(b"MThd");
+    file.extend(6u32.to_be_bytes());
+    file.extend(0u16.to_be_bytes());
+    file.extend(1u16.to_be_bytes());
+    file.extend((TICKS_PER_BEAT as u16).to_be_bytes());
+    file.extend(b"MTrk");
+    file.extend((data.len() as u32).to_be_bytes());
+    file.extend(data);
+    file
+}
+
+fn write_variable_length(data: &mut Vec<u8>, mut value: u32) {
+    let mut bytes = vec![(value & 0x7f) as u8];
+    value >>= 7;
+    while value > 0 {
+        bytes.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    data.extend(bytes.iter().rev());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        automaton::tests::automaton,
+        note::{Accidental, Note, NoteLetter},
+    };
+
+    const NOTE_ON: [u8; 3] = [0x90, 60, 60];
+    const NOTE_OFF: [u8; 3] = [0x80, 60, 0];
+
+    /// Exports `steps` generations of `automaton`, with every node playing
+    /// C4, at `bpm` with one generation per beat.
+    fn render(mut automaton: Automaton, steps: usize, bpm: f32) -> Vec<u8> {
+        for node in automaton.graph.nodes.values_mut() {
+            node.note = Some(Note::new(NoteLetter::C, Accidental::Neutral, 4));
+        }
+        let tempo = Tempo {
+            bpm,
+            subdivision: 1,
+        };
+        export(&automaton, steps, &tempo, &Tuning::default())
+    }
+
+    fn u32_at(file: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(file[offset..offset + 4].try_into().unwrap())
+    }
+
+    /// Reads a variable-length number and returns it with the bytes after
+    /// it.
+    fn read_variable_length(data: &[u8]) -> (u32, &[u8]) {
+        let mut value = 0;
+        for (index, byte) in data.iter().enumerate() {
+            value = value << 7 | (byte & 0x7f) as u32;
+            if byte & 0x80 == 0 {
+                return (value, &data[index + 1..]);
+            }
+        }
+        panic!("unterminated variable-length number");
+    }
+
+    /// The delta times and messages of the track, leaving out the tempo and
+    /// end of track events.
+    fn events(file: &[u8]) -> Vec<(u32, Vec<u8>)> {
+        let mut data = &file[22..];
+        let mut events = vec![];
+        while !data.is_empty() {
+            let delta;
+            (delta, data) = read_variable_length(data);
+            let length = match data[0] {
+                0xff => 3 + data[2] as usize,
+                _ => 3,
+            };
+            if data[0] != 0xff {
+                events.push((delta, data[..length].to_vec()));
+            }
+            data = &data[length..];
+        }
+        events
+    }
+
+    #[test]
+    fn header_and_track_lengths_match() {
+        let file = render(
+            automaton("blink state = 0: on", &[(0, "blink", &[])]),
+            3,
+            120.0,
+        );
+        assert_eq!(&file[0..4], b"MThd");
+        assert_eq!(u32_at(&file, 4), 6);
+        // Format 0, one track, 480 ticks per beat.
+        assert_eq!(file[8..14], [0, 0, 0, 1, 0x01, 0xe0]);
+        assert_eq!(&file[14..18], b"MTrk");
+        assert_eq!(u32_at(&file, 18) as usize, file.len() - 22);
+        assert!(file.ends_with(&[0x00, 0xff, 0x2f, 0x00]));
+    }
+
+    #[test]
+    fn tempo_is_written_in_microseconds_per_beat() {
+        let tempo = |bpm| render(automaton("", &[]), 0, bpm)[22..29].to_vec();
+        // 500000 microseconds.
+        assert_eq!(tempo(120.0), [0x00, 0xff, 0x51, 0x03, 0x07, 0xa1, 0x20]);
+        // 30000000 microseconds doesn't fit, so the slowest tempo is used.
+        assert_eq!(tempo(2.0), [0x00, 0xff, 0x51, 0x03, 0xff, 0xff, 0xff]);
+        assert_eq!(tempo(0.0), tempo(1.0));
+    }
+
+    #[test]
+    fn events_are_timed_by_generation() {
+        let file = render(
+            automaton("blink state = 0: on", &[(0, "blink", &[])]),
+            3,
+            120.0,
+        );
+        assert_eq!(
+            events(&file),
+            [
+                (0, NOTE_ON.to_vec()),
+                (480, NOTE_OFF.to_vec()),
+                (480, NOTE_ON.to_vec()),
+                // The note still sounding is ended after the last generation.
+                (480, NOTE_OFF.to_vec()),
+            ]
+        );
+        // 480 is written in two bytes.
+        assert_eq!(file[33..35], [0x83, 0x60]);
+    }
+
+    #[test]
+    fn note_offs_come_before_note_ons() {
+        // Two nodes on the same pitch taking turns.
+        let automaton = automaton("copy on > 0: on", &[(1, "copy", &[1]), (0, "copy", &[0])]);
+        let file = render(automaton, 2, 120.0);
+        assert_eq!(
+            events(&file),
+            [
+                (0, NOTE_ON.to_vec()),
+                (480, NOTE_OFF.to_vec()),
+                (0, NOTE_ON.to_vec()),
+                (480, NOTE_OFF.to_vec()),
+            ]
+        );
+    }
+}
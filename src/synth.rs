@@ -0,0 +1,336 @@
+use std::f64::consts::TAU;
+
+use crate::{midi::MidiSink, note::NoteEvent};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+pub enum Waveform {
+    #[default]
+    Sine,
+    Saw,
+    Square,
+}
+
+impl Waveform {
+    pub const ALL: [Waveform; 3] = [Waveform::Sine, Waveform::Saw, Waveform::Square];
+
+    /// The value at `phase`, which goes from 0 to 1 over one period.
+    fn sample(&self, phase: f64) -> f64 {
+        match self {
+            Waveform::Sine => (phase * TAU).sin(),
+            Waveform::Saw => 2.0 * phase - 1.0,
+            Waveform::Square => {
+                if phase < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+        }
+    }
+}
+
+/// How the loudness of a note changes over time. Times are in seconds.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Envelope {
+    pub attack: f64,
+    pub decay: f64,
+    /// The level held after the decay, from 0 to 1.
+    pub sustain: f64,
+    pub release: f64,
+}
+
+impl Default for Envelope {
+    fn default() -> Self {
+        Self {
+            attack: 0.01,
+            decay: 0.1,
+            sustain: 0.7,
+            release: 0.3,
+        }
+    }
+}
+
+impl Envelope {
+    /// The level of a note that has been held for `age` seconds.
+    fn held(&self, age: f64) -> f64 {
+        if age < self.attack {
+            age / self.attack
+        } else if age < self.attack + self.decay {
+            1.0 - (1.0 - self.sustain) * (age - self.attack) / self.decay
+        } else {
+            self.sustain
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct SynthSettings {
+    pub waveform: Waveform,
+    pub envelope: Envelope,
+    /// The loudness of a single note at full velocity, from 0 to 1.
+    pub volume: f64,
+}
+
+impl Default for SynthSettings {
+    fn default() -> Self {
+        Self {
+            waveform: Waveform::default(),
+            envelope: Envelope::default(),
+            volume: 0.2,
+        }
+    }
+}
+
+/// A note being played by the synth.
+#[derive(Clone, Debug)]
+struct Voice {
+    channel: u8,
+    number: u8,
+    frequency: f64,
+    /// From 0 to 1.
+    velocity: f64,
+    phase: f64,
+    /// Seconds since the note started.
+    age: f64,
+    /// Seconds since the note was released, and its level at that moment.
+    released: Option<(f64, f64)>,
+}
+
+/// A polyphonic synthesizer that plays the MIDI messages sent to it.
+///
+/// Notes are tuned to equal temperament with A4 at 440 Hz, moved by pitch
+/// bend, like on an external synth.
+#[derive(Clone, Debug)]
+pub struct Synth {
+    pub settings: SynthSettings,
+    sample_rate: f64,
+    /// How many semitones full pitch bend moves notes by.
+    bend_range: f64,
+    /// The current pitch bend of each channel, in semitones.
+    bends: [f64; 16],
+    voices: Vec<Voice>,
+}
+
+impl Synth {
+    pub fn new(settings: SynthSettings, sample_rate: u32, bend_range: f64) -> Self {
+        Self {
+            settings,
+            sample_rate: sample_rate as f64,
+            bend_range,
+            bends: [0.0; 16],
+            voices: vec![],
+        }
+    }
+
+    /// Fills `samples` with the next samples of the sound, from -1 to 1.
+    pub fn render(&mut self, samples: &mut [f32]) {
+        let step = 1.0 / self.sample_rate;
+        let SynthSettings {
+            waveform,
+            envelope,
+            volume,
+        } = self.settings;
+        for sample in samples.iter_mut() {
+            let mut value = 0.0;
+            for voice in &mut self.voices {
+                let level = match voice.released {
+                    Some((time, from)) => from * (1.0 - time / envelope.release).max(0.0),
+                    None => envelope.held(voice.age),
+                };
+                value += waveform.sample(voice.phase) * level * voice.velocity * volume;
+
+                voice.phase = (voice.phase + voice.frequency * step).fract();
+                voice.age += step;
+                if let Some((time, _)) = &mut voice.released {
+                    *time += step;
+                }
+            }
+            self.voices.retain(|voice| {
+                voice
+                    .released
+                    .is_none_or(|(time, _)| time < envelope.release)
+            });
+            *sample = value.clamp(-1.0, 1.0) as f32;
+        }
+    }
+
+    /// Whether any note is still sounding, including ones being released.
+    pub fn is_sounding(&self) -> bool {
+        !self.voices.is_empty()
+    }
+}
+
+impl MidiSink for Synth {
+    fn send(&mut self, message: &[u8]) {
+        match NoteEvent::decode(message) {
+            Some(NoteEvent::On {
+                channel,
+                number,
+                velocity,
+            }) => {
+                let pitch = number as f64 + self.bends[channel as usize];
+                self.voices.push(Voice {
+                    channel,
+                    number,
+                    frequency: 440.0 * 2f64.powf((pitch - 69.0) / 12.0),
+                    velocity: velocity as f64 / 127.0,
+                    phase: 0.0,
+                    age: 0.0,
+                    released: None,
+                });
+            }
+            Some(NoteEvent::Off { channel, number }) => {
+                let envelope = self.settings.envelope;
+                for voice in &mut self.voices {
+                    if voice.channel == channel
+                        && voice.number == number
+                        && voice.released.is_none()
+                    {
+                        voice.released = Some((0.0, envelope.held(voice.age)));
+                    }
+                }
+            }
+            None => {
+                if let Ok((
+                    midi_msg::MidiMsg::ChannelVoice {
+                        channel,
+                        msg: midi_msg::ChannelVoiceMsg::PitchBend { bend },
+                    },
+                    _,
+                )) = midi_msg::MidiMsg::from_midi(message)
+                {
+                    self.bends[channel as usize] =
+                        (bend as f64 - 8192.0) / 8192.0 * self.bend_range;
+                }
+            }
+        }
+    }
+}
+
+/// The built-in synth playing through the sound card, for hearing the graph
+/// without any MIDI gear.
+#[cfg(all(feature = "audio-out", target_os = "linux"))]
+pub struct AudioOutput {
+    synth: std::sync::Arc<std::sync::Mutex<Synth>>,
+    /// Tells the thread feeding the sound card to stop.
+    running: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    /// The thread feeding the sound card, until its error has been taken.
+    feeder: Option<std::thread::JoinHandle<alsa::Result<()>>>,
+}
+
+#[cfg(all(feature = "audio-out", target_os = "linux"))]
+impl AudioOutput {
+    /// Samples per second asked of the sound card.
+    const SAMPLE_RATE: u32 = 44100;
+    /// Samples rendered at a time. Small enough to keep notes in time with
+    /// the generations.
+    const PERIOD: usize = 256;
+
+    /// Opens the default sound card and starts playing a synth with
+    /// `settings` on it.
+    pub fn connect(settings: SynthSettings, bend_range: f64) -> Result<Self, String> {
+        use alsa::pcm::{Access, Format, HwParams, PCM};
+        use alsa::{Direction, ValueOr};
+        use std::sync::{atomic::AtomicBool, Arc, Mutex};
+
+        let pcm = PCM::new("default", Direction::Playback, false).map_err(|e| e.to_string())?;
+        let sample_rate = {
+            let params = HwParams::any(&pcm).map_err(|e| e.to_string())?;
+            params.set_channels(1).map_err(|e| e.to_string())?;
+            params
+                .set_format(Format::s16())
+                .map_err(|e| e.to_string())?;
+            params
+                .set_access(Access::RWInterleaved)
+                .map_err(|e| e.to_string())?;
+            let sample_rate = params
+                .set_rate_near(Self::SAMPLE_RATE, ValueOr::Nearest)
+                .map_err(|e| e.to_string())?;
+            params
+                .set_period_size_near(Self::PERIOD as alsa::pcm::Frames, ValueOr::Nearest)
+                .map_err(|e| e.to_string())?;
+            params
+                .set_buffer_size_near(4 * Self::PERIOD as alsa::pcm::Frames)
+                .map_err(|e| e.to_string())?;
+            pcm.hw_params(&params).map_err(|e| e.to_string())?;
+            sample_rate
+        };
+
+        let synth = Arc::new(Mutex::new(Synth::new(settings, sample_rate, bend_range)));
+        let running = Arc::new(AtomicBool::new(true));
+        Ok(Self {
+            synth: synth.clone(),
+            running: running.clone(),
+            feeder: Some(std::thread::spawn(move || {
+                Self::feed(&pcm, &synth, &running)
+            })),
+        })
+    }
+
+    /// Renders the synth into the sound card until told to stop. Writing
+    /// blocks while the sound card's buffer is full, which keeps the synth
+    /// in step with it.
+    fn feed(
+        pcm: &alsa::PCM,
+        synth: &std::sync::Mutex<Synth>,
+        running: &std::sync::atomic::AtomicBool,
+    ) -> alsa::Result<()> {
+        use std::sync::atomic::Ordering;
+
+        let io = pcm.io_i16()?;
+        let mut samples = [0.0; Self::PERIOD];
+        let mut frames = [0; Self::PERIOD];
+        while running.load(Ordering::Relaxed) {
+            match synth.lock() {
+                Ok(mut synth) => synth.render(&mut samples),
+                Err(_) => return Ok(()),
+            }
+            for (frame, sample) in frames.iter_mut().zip(samples) {
+                *frame = (sample * i16::MAX as f32) as i16;
+            }
+            if let Err(error) = io.writei(&frames) {
+                // Recovers from underruns, which happen when the window
+                // stalls for a moment.
+                pcm.try_recover(error, true)?;
+            }
+        }
+        pcm.drain()
+    }
+
+    /// Makes the synth follow changes to `settings` and the bend range.
+    pub fn update(&self, settings: &SynthSettings, bend_range: f64) {
+        if let Ok(mut synth) = self.synth.lock() {
+            synth.settings = settings.clone();
+            synth.bend_range = bend_range;
+        }
+    }
+}
+
+#[cfg(all(feature = "audio-out", target_os = "linux"))]
+impl MidiSink for AudioOutput {
+    fn send(&mut self, message: &[u8]) {
+        if let Ok(mut synth) = self.synth.lock() {
+            synth.send(message);
+        }
+    }
+
+    /// The error that stopped the sound, once the sound card has failed.
+    fn take_error(&mut self) -> Option<String> {
+        if !self.feeder.as_ref()?.is_finished() {
+            return None;
+        }
+        match self.feeder.take()?.join() {
+            Ok(Ok(())) => None,
+            Ok(Err(error)) => Some(error.to_string()),
+            Err(_) => Some("the audio thread panicked".to_string()),
+        }
+    }
+}
+
+#[cfg(all(feature = "audio-out", target_os = "linux"))]
+impl Drop for AudioOutput {
+    fn drop(&mut self) {
+        self.running
+            .store(false, std::sync::atomic::Ordering::Relaxed);
+    }
+}
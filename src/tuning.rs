@@ -0,0 +1,322 @@
+use crate::note::{Accidental, Note, NoteLetter};
+
+/// How notes are turned into frequencies.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Tuning {
+    /// The note that sounds at `frequency`.
+    pub reference: Note,
+    /// In Hz.
+    pub frequency: f64,
+    /// The note the first degree of `scale` falls on. Its octave doesn't
+    /// matter.
+    pub root: Note,
+    pub scale: Scale,
+    /// How many semitones a receiver bends the pitch by at full pitch bend.
+    pub bend_range: f64,
+}
+
+impl Default for Tuning {
+    fn default() -> Self {
+        Self {
+            reference: Note::new(NoteLetter::A, Accidental::Neutral, 4),
+            frequency: 440.0,
+            root: Note::new(NoteLetter::C, Accidental::Neutral, 4),
+            scale: Scale::EqualTemperament,
+            bend_range: 2.0,
+        }
+    }
+}
+
+impl Tuning {
+    /// The frequency of `note` in Hz.
+    pub fn frequency(&self, note: &Note) -> f64 {
+        let cents = self.cents(note.key()) - self.cents(self.reference.key());
+        self.frequency * 2f64.powf(cents / 1200.0)
+    }
+
+    /// The MIDI note number and pitch bend that play `note` on a receiver
+    /// tuned to equal temperament with A4 at 440 Hz, or `None` if the note is
+    /// out of the MIDI range.
+    pub fn midi_pitch(&self, note: &Note) -> Option<(u8, u16)> {
+        let pitch = 69.0 + 12.0 * (self.frequency(note) / 440.0).log2();
+        let number = pitch.round();
+        if !(0.0..=127.0).contains(&number) {
+            return None;
+        }
+        let bend = 8192.0 + (pitch - number) / self.bend_range * 8192.0;
+        Some((number as u8, bend.round().clamp(0.0, 16383.0) as u16))
+    }
+
+    /// Whether every note lands on a key of an equal tempered keyboard with A4
+    /// at 440 Hz, so no pitch bend is needed.
+    pub fn is_standard(&self) -> bool {
+        let standard = 440.0 * 2f64.powf((self.reference.key() - 69) as f64 / 12.0);
+        self.scale == Scale::EqualTemperament && (self.frequency - standard).abs() < 1e-6
+    }
+
+    /// How many cents the note with `key` is above the root in octave -1.
+    fn cents(&self, key: i32) -> f64 {
+        let degrees = self.scale.degrees();
+        let steps = degrees.len() as i32;
+        let period = degrees[degrees.len() - 1];
+        let from_root = key - self.root.key().rem_euclid(12);
+        let degree = from_root.rem_euclid(steps) as usize;
+        let below = if degree == 0 {
+            0.0
+        } else {
+            degrees[degree - 1]
+        };
+        from_root.div_euclid(steps) as f64 * period + below
+    }
+}
+
+/// The pitches of one period of a scale, like the keys of one octave.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum Scale {
+    EqualTemperament,
+    /// Five-limit just intonation.
+    JustIntonation,
+    /// A scale read from a Scala file. `degrees` are in cents above the
+    /// root, from the second degree up to the period, which is usually the
+    /// octave at 1200.
+    Scala {
+        description: String,
+        degrees: Vec<f64>,
+    },
+}
+
+/// The twelve ratios of [`Scale::JustIntonation`], from the minor second to
+/// the octave.
+const JUST_RATIOS: [(f64, f64); 12] = [
+    (16.0, 15.0),
+    (9.0, 8.0),
+    (6.0, 5.0),
+    (5.0, 4.0),
+    (4.0, 3.0),
+    (45.0, 32.0),
+    (3.0, 2.0),
+    (8.0, 5.0),
+    (5.0, 3.0),
+    (9.0, 5.0),
+    (15.0, 8.0),
+    (2.0, 1.0),
+];
+
+impl Scale {
+    /// Reads a scale in the Scala `.scl` format: a description, the number of
+    /// degrees, then one degree per line as cents (with a `.`) or as a ratio
+    /// like `3/2`. Lines starting with `!` are comments.
+    pub fn parse_scala(text: &str) -> Result<Self, String> {
+        let mut lines = text
+            .lines()
+            .filter(|line| !line.starts_with('!'))
+            .map(str::trim);
+        let description = lines.next().ok_or("missing description")?.to_string();
+        let count = lines.next().ok_or("missing number of notes")?;
+        let count: usize = count
+            .split_whitespace()
+            .next()
+            .and_then(|count| count.parse().ok())
+            .ok_or_else(|| format!("invalid number of notes {count:?}"))?;
+        if count == 0 {
+            return Err("a scale needs at least one note".to_string());
+        }
+
+        let mut degrees = vec![];
+        for line in lines.filter(|line| !line.is_empty()).take(count) {
+            let pitch = line.split_whitespace().next().unwrap_or_default();
+            degrees.push(parse_pitch(pitch).ok_or_else(|| format!("invalid pitch {pitch:?}"))?);
+        }
+        if degrees.len() < count {
+            return Err(format!(
+                "expected {count} notes but found {}",
+                degrees.len()
+            ));
+        }
+        if degrees[count - 1] <= 0.0 {
+            return Err("the last note must be above the root".to_string());
+        }
+        Ok(Scale::Scala {
+            description,
+            degrees,
+        })
+    }
+
+    /// The name shown for the scale.
+    pub fn name(&self) -> &str {
+        match self {
+            Scale::EqualTemperament => "equal temperament",
+            Scale::JustIntonation => "just intonation",
+            Scale::Scala { description, .. } if !description.is_empty() => description,
+            Scale::Scala { .. } => "scala scale",
+        }
+    }
+
+    /// The degrees in cents, from the second one up to the period.
+    fn degrees(&self) -> Vec<f64> {
+        match self {
+            Scale::EqualTemperament => (1..=12).map(|step| step as f64 * 100.0).collect(),
+            Scale::JustIntonation => JUST_RATIOS
+                .iter()
+                .map(|(numerator, denominator)| ratio_to_cents(numerator / denominator))
+                .collect(),
+            Scale::Scala { degrees, .. } => degrees.clone(),
+        }
+    }
+}
+
+/// Reads a Scala pitch: cents if it has a `.`, otherwise a ratio or a whole
+/// number.
+fn parse_pitch(pitch: &str) -> Option<f64> {
+    if pitch.contains('.') {
+        return pitch.parse().ok();
+    }
+    let (numerator, denominator) = pitch.split_once('/').unwrap_or((pitch, "1"));
+    let numerator: f64 = numerator.parse::<u64>().ok()? as f64;
+    let denominator: f64 = denominator.parse::<u64>().ok()? as f64;
+    (numerator > 0.0 && denominator > 0.0).then(|| ratio_to_cents(numerator / denominator))
+}
+
+fn ratio_to_cents(ratio: f64) -> f64 {
+    1200.0 * ratio.log2()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(text: &str) -> Note {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn midi_numbers_put_middle_c_at_60() {
+        assert_eq!(note("C4").to_midi_number(), Some(60));
+        assert_eq!(note("C#4").to_midi_number(), Some(61));
+        assert_eq!(note("A4").to_midi_number(), Some(69));
+        assert_eq!(note("C-1").to_midi_number(), Some(0));
+        assert_eq!(note("G9").to_midi_number(), Some(127));
+        assert_eq!(note("G#9").to_midi_number(), None);
+        assert_eq!(note("Cb-1").to_midi_number(), None);
+    }
+
+    #[test]
+    fn accidentals_can_cross_octaves() {
+        // B#3 is the same key as C4, and Cb4 the same as B3.
+        assert_eq!(note("B#3").to_midi_number(), Some(60));
+        assert_eq!(note("Cb4").to_midi_number(), Some(59));
+        assert_eq!(note("B##3").to_midi_number(), Some(61));
+        assert_eq!(note("Cbb4").to_midi_number(), Some(58));
+    }
+
+    #[test]
+    fn equal_temperament_needs_no_bend() {
+        let tuning = Tuning::default();
+        assert!(tuning.is_standard());
+        assert_eq!(tuning.midi_pitch(&note("C4")), Some((60, 8192)));
+        assert_eq!(tuning.midi_pitch(&note("C#4")), Some((61, 8192)));
+        assert_eq!(tuning.midi_pitch(&note("B#3")), Some((60, 8192)));
+        assert_eq!(tuning.midi_pitch(&note("G#9")), None);
+        assert!((tuning.frequency(&note("A4")) - 440.0).abs() < 1e-9);
+        assert!((tuning.frequency(&note("C4")) - 261.6256).abs() < 1e-4);
+    }
+
+    #[test]
+    fn reference_pitch_moves_every_note() {
+        let tuning = Tuning {
+            frequency: 432.0,
+            ..Tuning::default()
+        };
+        assert!(!tuning.is_standard());
+        assert!((tuning.frequency(&note("A3")) - 216.0).abs() < 1e-9);
+        // 432 Hz is about 31.8 cents below 440 Hz.
+        assert_eq!(tuning.midi_pitch(&note("A4")), Some((69, 6891)));
+    }
+
+    #[test]
+    fn just_intervals_are_bent_into_place() {
+        let tuning = Tuning {
+            scale: Scale::JustIntonation,
+            ..Tuning::default()
+        };
+        assert!(!tuning.is_standard());
+        let c4 = tuning.frequency(&note("C4"));
+        // A4 stays at 440 Hz, a just major sixth above C4.
+        assert!((c4 - 264.0).abs() < 1e-9);
+        assert!((tuning.frequency(&note("E4")) / c4 - 5.0 / 4.0).abs() < 1e-12);
+        assert!((tuning.frequency(&note("G4")) / c4 - 3.0 / 2.0).abs() < 1e-12);
+        assert!((tuning.frequency(&note("C5")) / c4 - 2.0).abs() < 1e-12);
+        // The pure third is about 2 cents above the equal tempered E4.
+        assert_eq!(tuning.midi_pitch(&note("E4")), Some((64, 8272)));
+        assert_eq!(tuning.midi_pitch(&note("C4")), Some((60, 8833)));
+        // A wider bend range needs less bend for the same interval.
+        let wide = Tuning {
+            bend_range: 12.0,
+            ..tuning
+        };
+        assert_eq!(wide.midi_pitch(&note("C4")), Some((60, 8299)));
+    }
+
+    const MEANTONE: &str = "! meantone.scl
+!
+1/4-comma meantone scale. Pietro Aaron's temperament (1523)
+ 12
+!
+ 76.04900
+ 193.15686
+ 310.26471
+ 5/4
+ 503.42157
+ 579.47057
+ 696.57843
+ 25/16
+ 889.73529
+ 1006.84314
+ 1082.89214
+ 2/1
+";
+
+    #[test]
+    fn scala_files_are_read() {
+        let scale = Scale::parse_scala(MEANTONE).unwrap();
+        assert_eq!(
+            scale.name(),
+            "1/4-comma meantone scale. Pietro Aaron's temperament (1523)"
+        );
+        let degrees = scale.degrees();
+        assert_eq!(degrees.len(), 12);
+        assert_eq!(degrees[0], 76.049);
+        assert!((degrees[3] - 386.3137).abs() < 1e-4);
+        assert!((degrees[11] - 1200.0).abs() < 1e-9);
+
+        // The scale's E4 is a pure third above its C4.
+        let tuning = Tuning {
+            scale,
+            ..Tuning::default()
+        };
+        let ratio = tuning.frequency(&note("E4")) / tuning.frequency(&note("C4"));
+        assert!((ratio - 1.25).abs() < 1e-12);
+    }
+
+    #[test]
+    fn scala_pitches_can_have_comments_and_whole_numbers() {
+        let scale = Scale::parse_scala("fifths\n2\n701.955 fifth\n2 octave\n").unwrap();
+        assert_eq!(
+            scale,
+            Scale::Scala {
+                description: "fifths".to_string(),
+                degrees: vec![701.955, 1200.0]
+            }
+        );
+    }
+
+    #[test]
+    fn bad_scala_files_are_rejected() {
+        assert!(Scale::parse_scala("").is_err());
+        assert!(Scale::parse_scala("empty\n0\n").is_err());
+        assert!(Scale::parse_scala("short\n3\n100.0\n2/1\n").is_err());
+        assert!(Scale::parse_scala("bad\n1\nthree halves\n").is_err());
+        assert!(Scale::parse_scala("zero\n1\n0/2\n").is_err());
+        assert!(Scale::parse_scala("down\n1\n-100.0\n").is_err());
+    }
+}
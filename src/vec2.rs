@@ -1,4 +1,6 @@
+#[cfg(feature = "gui")]
 use egui_macroquad::egui::{Pos2, Vec2};
+#[cfg(feature = "gui")]
 use macroquad::prelude::Vec2 as MacroquadVec2;
 use serde::{Deserialize, Serialize};
 use std::ops;
@@ -9,19 +11,21 @@ pub struct Vector2 {
     pub y: f32,
 }
 
-impl Into<Vec2> for Vector2 {
-    fn into(self) -> Vec2 {
+#[cfg(feature = "gui")]
+impl From<Vector2> for Vec2 {
+    fn from(value: Vector2) -> Vec2 {
         Vec2 {
-            x: self.x,
-            y: self.y,
+            x: value.x,
+            y: value.y,
         }
     }
 }
-impl Into<MacroquadVec2> for Vector2 {
-    fn into(self) -> MacroquadVec2 {
+#[cfg(feature = "gui")]
+impl From<Vector2> for MacroquadVec2 {
+    fn from(value: Vector2) -> MacroquadVec2 {
         MacroquadVec2 {
-            x: self.x,
-            y: self.y,
+            x: value.x,
+            y: value.y,
         }
     }
 }
@@ -107,11 +111,12 @@ impl Vector2 {
     }
 }
 
-impl Into<Pos2> for Vector2 {
-    fn into(self) -> Pos2 {
+#[cfg(feature = "gui")]
+impl From<Vector2> for Pos2 {
+    fn from(value: Vector2) -> Pos2 {
         Pos2 {
-            x: self.x,
-            y: self.y,
+            x: value.x,
+            y: value.y,
         }
     }
 }
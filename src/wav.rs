@@ -0,0 +1,157 @@
+use crate::{
+    automaton::Automaton,
+    clock::Tempo,
+    midi::Player,
+    synth::{Synth, SynthSettings},
+    tuning::Tuning,
+};
+
+/// Samples per second of rendered files.
+pub const SAMPLE_RATE: u32 = 44100;
+
+/// Runs a copy of `automaton` for `steps` generations at `tempo`, plays the
+/// notes, tuned with `tuning`, on the built-in synth and returns the sound as
+/// a 16-bit mono WAV file. The file ends once the last note has faded out.
+pub fn export(
+    automaton: &Automaton,
+    steps: usize,
+    tempo: &Tempo,
+    tuning: &Tuning,
+    settings: &SynthSettings,
+) -> Vec<u8> {
+    let mut automaton = automaton.clone();
+    let mut synth = Synth::new(settings.clone(), SAMPLE_RATE, tuning.bend_range);
+    let mut player = Player::new();
+    let mut samples = vec![];
+
+    for step in 0..steps {
+        let changes = automaton.step();
+        player.play(&changes, &automaton, tuning, &mut synth);
+        // Rounding each step's end, rather than its length, keeps long
+        // renders from drifting off the tempo.
+        let end = ((step + 1) as f64 * tempo.step_duration() * SAMPLE_RATE as f64).round();
+        let start = samples.len();
+        samples.resize((end as usize).max(start), 0.0);
+        synth.render(&mut samples[start..]);
+    }
+
+    player.stop(&mut synth);
+    while synth.is_sounding() {
+        let start = samples.len();
+        samples.resize(start + SAMPLE_RATE as usize / 10, 0.0);
+        synth.render(&mut samples[start..]);
+    }
+
+    encode(&samples)
+}
+
+/// Writes `samples`, from -1 to 1, as a 16-bit mono WAV file.
+fn encode(samples: &[f32]) -> Vec<u8> {
+    let data_length = samples.len() as u32 * 2;
+    let mut file = vec![];
+    file.extend(b"RIFF");
+    file.extend((36 + data_length).to_le_bytes());
+    file.extend(b"WAVE");
+    file.extend(b"fmt ");
+    file.extend(16u32.to_le_bytes());
+    // Uncompressed, one channel.
+    file.extend(1u16.to_le_bytes());
+    file.extend(1u16.to_le_bytes());
+    file.extend(SAMPLE_RATE.to_le_bytes());
+    file.extend((SAMPLE_RATE * 2).to_le_bytes());
+    file.extend(2u16.to_le_bytes());
+    file.extend(16u16.to_le_bytes());
+    file.extend(b"data");
+    file.extend(data_length.to_le_bytes());
+    for sample in samples {
+        file.extend(((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16).to_le_bytes());
+    }
+    file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        graph::NodeId,
+        note::{Accidental, Note, NoteLetter},
+    };
+
+    /// An automaton with one node that turns on and stays on, playing
+    /// `note`.
+    fn automaton(note: Option<Note>) -> Automaton {
+        let mut automaton =
+            crate::automaton::tests::automaton("up state >= 0: on", &[(0, "up", &[])]);
+        automaton.graph[NodeId(0)].note = note;
+        automaton
+    }
+
+    fn render(automaton: &Automaton, steps: usize) -> Vec<u8> {
+        let tempo = Tempo {
+            bpm: 120.0,
+            subdivision: 1,
+        };
+        export(
+            automaton,
+            steps,
+            &tempo,
+            &Tuning::default(),
+            &SynthSettings::default(),
+        )
+    }
+
+    fn u32_at(file: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(file[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn u16_at(file: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(file[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn samples(file: &[u8]) -> Vec<i16> {
+        file[44..]
+            .chunks(2)
+            .map(|sample| i16::from_le_bytes([sample[0], sample[1]]))
+            .collect()
+    }
+
+    #[test]
+    fn header_describes_the_samples() {
+        let file = render(&automaton(None), 2);
+        assert_eq!(&file[0..4], b"RIFF");
+        assert_eq!(u32_at(&file, 4) as usize, file.len() - 8);
+        assert_eq!(&file[8..16], b"WAVEfmt ");
+        assert_eq!(u32_at(&file, 16), 16);
+        assert_eq!(u16_at(&file, 20), 1);
+        assert_eq!(u16_at(&file, 22), 1);
+        assert_eq!(u32_at(&file, 24), SAMPLE_RATE);
+        assert_eq!(u32_at(&file, 28), SAMPLE_RATE * 2);
+        assert_eq!(u16_at(&file, 32), 2);
+        assert_eq!(u16_at(&file, 34), 16);
+        assert_eq!(&file[36..40], b"data");
+        assert_eq!(u32_at(&file, 40) as usize, file.len() - 44);
+    }
+
+    #[test]
+    fn length_follows_the_tempo() {
+        // Two generations at 120 bpm take a second, and nothing is left
+        // sounding after them.
+        let file = render(&automaton(None), 2);
+        assert_eq!(file.len(), 44 + SAMPLE_RATE as usize * 2);
+        assert!(samples(&file).iter().all(|&sample| sample == 0));
+    }
+
+    #[test]
+    fn notes_make_sound() {
+        let note = Note::new(NoteLetter::A, Accidental::Neutral, 4);
+        let file = render(&automaton(Some(note)), 2);
+        // The release of the last note runs on past the generations...
+        assert!(file.len() >= 44 + SAMPLE_RATE as usize * 2);
+        let samples = samples(&file);
+        let second = SAMPLE_RATE as usize;
+        assert!(samples[..second].iter().any(|&sample| sample.abs() > 1000));
+        // and fades out before the file ends.
+        let tail = &samples[samples.len() - 100..];
+        assert!(tail.iter().all(|&sample| sample.abs() < 100));
+    }
+}